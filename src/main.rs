@@ -1,14 +1,25 @@
 extern crate clap;
 extern crate preferences;
+extern crate app_dirs;
 use clap::{Arg, App};
 use std::io::prelude::*;
 use std::fs::File;
 use std::path::Path;
 use preferences::{AppInfo, PreferencesMap, Preferences};
+use app_dirs::{AppDataType, get_app_dir};
 mod migration;
 
 const APP_INFO: AppInfo = AppInfo{name: "mtmigrate", author: "mtmigrate"};
 
+fn read_torrent(torrent_file:&str) -> Vec<u8> {
+    let mut f = File::open(torrent_file).expect("Failed to open torrent file");
+    let path = Path::new(torrent_file);
+    let size = path.metadata().expect("Failed to access torrent file metadata").len() as usize;
+    let mut buffer = Vec::with_capacity(size);
+    f.read_to_end(&mut buffer).expect("Faled to load file into buffer");
+    buffer
+}
+
 fn main() {
     let matches = App::new("mtmigrate")
                     .version("0.1.0")
@@ -35,6 +46,34 @@ fn main() {
                         .required(false)
                         .index(3)
                         .takes_value(true))
+                    .arg(Arg::with_name("source-torrent")
+                        .long("source-torrent")
+                        .value_name("torrent file")
+                        .help("Torrent file the input was originally downloaded with, the input is verified against it first")
+                        .takes_value(true))
+                    .arg(Arg::with_name("link")
+                        .long("link")
+                        .help("Hardlink files that are identical to their input instead of copying them, so both torrents share the storage"))
+                    .arg(Arg::with_name("reflink")
+                        .long("reflink")
+                        .conflicts_with("link")
+                        .help("Clone data from the input with copy-on-write reflinks where the filesystem supports it (btrfs, XFS)"))
+                    .arg(Arg::with_name("in-place")
+                        .long("in-place")
+                        .conflicts_with_all(&["link", "reflink"])
+                        .help("Transform the input directory into the new torrent instead of writing a copy to the output, the input no longer matches the old torrent afterwards"))
+                    .arg(Arg::with_name("merge")
+                        .long("merge")
+                        .conflicts_with_all(&["link", "reflink", "in-place"])
+                        .help("Merge into a download of the torrent that is in progress in the output, only pieces that fail there and verify from the input are written"))
+                    .arg(Arg::with_name("verified-only")
+                        .long("verified-only")
+                        .conflicts_with_all(&["in-place", "merge"])
+                        .help("Only write the data of pieces that verified, the rest of the files is left as sparse holes for the client to download"))
+                    .arg(Arg::with_name("preallocate")
+                        .long("preallocate")
+                        .conflicts_with_all(&["in-place", "merge"])
+                        .help("Allocate the output files completely with fallocate instead of leaving unwritten parts sparse, for clients that expect fully allocated files"))
                     .get_matches();
 
     // determine configuration
@@ -62,12 +101,24 @@ fn main() {
         }
     };
 
-    // read the torrent file into a byte vector
-    let mut f = File::open(torrent_file).expect("Failed to open torrent file");
-    let path = Path::new(torrent_file);
-    let size = path.metadata().expect("Failed to access torrent file metadata").len() as usize;
-    let mut buffer = Vec::with_capacity(size);
-    f.read_to_end(&mut buffer).expect("Faled to load file into buffer");
+    // read the torrent files into byte vectors
+    let buffer = read_torrent(torrent_file);
+    let source_torrent = matches.value_of("source-torrent").map(read_torrent);
+
+    // piece results are cached between runs in the platform specific cache directory
+    let cache_dir = get_app_dir(AppDataType::UserCache, &APP_INFO, "pieces").ok();
 
-    migration::run(buffer, input, output).expect("Migration failed");
+    let strategy = if matches.is_present("link") {
+        migration::Strategy::Hardlink
+    } else if matches.is_present("reflink") {
+        migration::Strategy::Reflink
+    } else {
+        migration::Strategy::Copy
+    };
+    let in_place = matches.is_present("in-place");
+    let merge = matches.is_present("merge");
+    let verified_only = matches.is_present("verified-only");
+    let preallocate = matches.is_present("preallocate");
+    let options = migration::Options { cache_dir, source_torrent, strategy, in_place, merge, verified_only, preallocate };
+    migration::run(buffer, input, output, options).expect("Migration failed");
 }
\ No newline at end of file
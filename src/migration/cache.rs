@@ -0,0 +1,133 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::bip_metainfo::{Metainfo};
+use migration::layout::TorrentLayout;
+use migration::overlay;
+use migration::sha1::{Sha1, Digest};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+static CACHE_HEADER:&str = "mtmigrate piece cache 1";
+// results kept per piece, this covers the configurations tried during a typical run
+const ENTRIES_PER_PIECE:usize = 4;
+
+// piece results of earlier runs, stored per torrent in a file named after the infohash
+// every result is keyed by the identity of the inputs it was assembled from, so a changed
+// input or offset simply doesn't match anymore, deleting the cache files is always safe
+pub struct PieceCache {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<usize, Vec<(String, bool)>>>, // most recent first
+}
+
+// the identity of an input as far as the cache is concerned
+#[derive(Debug, Clone)]
+pub struct InputIdentity {
+    path: String,
+    size: u64,
+    modified: u128,
+}
+
+fn to_hex(bytes:&[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl PieceCache {
+    // load the cache for a torrent, without a directory nothing is persisted
+    pub fn load(dir:Option<PathBuf>, torrent_meta:&Metainfo) -> PieceCache {
+        let path = dir.map(|mut d| {
+            d.push(to_hex(torrent_meta.info().info_hash().as_ref()));
+            d
+        });
+        let mut entries:HashMap<usize, Vec<(String, bool)>> = HashMap::new();
+        if let Some(file) = path.as_ref().and_then(|p| File::open(p).ok()) {
+            let mut lines = BufReader::new(file).lines();
+            if lines.next().and_then(|l| l.ok()).is_some_and(|l| l == CACHE_HEADER) {
+                // ignore anything we can't parse, those pieces are just hashed again
+                for line in lines.map_while(Result::ok) {
+                    let parts:Vec<&str> = line.split(' ').collect();
+                    if parts.len() != 3 {
+                        continue;
+                    }
+                    if let Ok(piece) = parts[0].parse::<usize>() {
+                        entries.entry(piece).or_default().push((parts[1].to_string(), parts[2] == "1"));
+                    }
+                }
+            }
+        }
+        PieceCache { path, entries:Mutex::new(entries) }
+    }
+
+    pub fn get(&self, piece:usize, key:&str) -> Option<bool> {
+        self.entries.lock().unwrap().get(&piece).and_then(|e| e.iter().find(|x| x.0 == key).map(|x| x.1))
+    }
+
+    pub fn insert(&self, piece:usize, key:String, success:bool) {
+        let mut entries = self.entries.lock().unwrap();
+        let piece_entries = entries.entry(piece).or_default();
+        piece_entries.retain(|x| x.0 != key);
+        piece_entries.insert(0, (key, success));
+        piece_entries.truncate(ENTRIES_PER_PIECE);
+    }
+
+    // write the cache to disk, failing to do so only costs time on the next run
+    pub fn save(&self) {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return,
+        };
+        let entries = self.entries.lock().unwrap();
+        let mut pieces:Vec<&usize> = entries.keys().collect();
+        pieces.sort();
+        let temp = path.with_extension("tmp");
+        let result = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            writeln!(writer, "{}", CACHE_HEADER)?;
+            for piece in pieces {
+                for &(ref key, success) in &entries[piece] {
+                    writeln!(writer, "{} {} {}", piece, key, if success { 1 } else { 0 })?;
+                }
+            }
+            writer.flush()?;
+            fs::rename(&temp, path)
+        });
+        if let Err(e) = result {
+            println!("Unable to write piece cache: {}", e);
+        }
+    }
+}
+
+// look up the identities of the inputs once, None for inputs we can't access
+pub fn input_identities(inputs:&[SourceFile]) -> Vec<Option<InputIdentity>> {
+    inputs.iter().map(|input| {
+        let metadata = fs::metadata(&input.path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let path = fs::canonicalize(&input.path).unwrap_or_else(|_| input.path.clone());
+        // derived data read from the file of another input is told apart by its segments
+        let mut path = path.to_string_lossy().into_owned();
+        if let Some(ref segments) = input.segments {
+            path = format!("{}#{}", path, overlay::digest(segments));
+        }
+        Some(InputIdentity {
+            path,
+            size: metadata.len(),
+            modified: modified.as_secs() as u128 * 1_000_000_000 + modified.subsec_nanos() as u128,
+        })
+    }).collect()
+}
+
+// the cache key of a piece, this covers every input range and offset that makes up the piece
+pub fn piece_key(layout:&TorrentLayout, identities:&[Option<InputIdentity>], targets:&[TargetFile], piece:usize) -> Option<String> {
+    let mut hasher = Sha1::new();
+    for file in layout.piece_files(piece) {
+        let target = &targets[file];
+        let identity = identities[target.mapping?].as_ref()?;
+        let part = layout.file_part(piece, file);
+        hasher.input(format!("{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\n",
+            file, identity.path, identity.size, identity.modified, target.offset, target.size, part.start, part.end).as_bytes());
+    }
+    Some(to_hex(hasher.result().as_slice()))
+}
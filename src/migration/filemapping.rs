@@ -1,5 +1,6 @@
 use ::migration::{SourceFile, TargetFile};
 use std::io;
+use std::path::PathBuf;
 use std::io::Write;
 
 pub fn create_mapping(mut inputs: &mut Vec<SourceFile>, targets: &mut Vec<TargetFile>) {
@@ -45,11 +46,27 @@ pub fn create_mapping(mut inputs: &mut Vec<SourceFile>, targets: &mut Vec<Target
     }
 
     // assign the mapping to the targets as well
+    assign_mapping(inputs, targets);
+}
+
+// assign the mapping of the inputs to the targets, returns the targets whose mapping changed
+pub fn assign_mapping(inputs: &[SourceFile], targets: &mut [TargetFile]) -> Vec<usize> {
+    let mut mappings:Vec<Option<usize>> = vec![None; targets.len()];
     for (i, input) in inputs.iter().enumerate() {
         if let Some(mapping) = input.mapping {
-            targets[mapping].mapping = Some(i);
+            mappings[mapping] = Some(i);
+        }
+    }
+    let mut changed = Vec::new();
+    for (target, mapping) in targets.iter_mut().zip(mappings) {
+        if target.mapping != mapping {
+            // an offset found for the previous input doesn't apply to the new one
+            target.mapping = mapping;
+            target.offset = 0;
+            changed.push(target.index);
         }
     }
+    changed
 }
 
 // output the mapping
@@ -80,11 +97,13 @@ fn print_mapping(inputs: &Vec<SourceFile>, targets: &Vec<TargetFile>) {
     println!("Total size difference: {:.1} kB / {:.2}%", total_diff as f64 / 1024.0, (total_diff / total_size) * 100.0);
 }
 
-// map by the filename
+// map by the filename, inputs that were renamed since the source torrent use their name in that torrent
 fn map_by_filename(inputs: &mut Vec<SourceFile>, targets: &Vec<TargetFile>) {
     let mut targets_audio:Vec<&TargetFile> = targets.iter().filter(|f| f.is_audio).collect();
     targets_audio.sort_by(|a, b| a.path.cmp(&b.path));
-    for (i, input) in inputs.iter_mut().filter(|f| f.is_audio).enumerate() {
+    let mut inputs_audio:Vec<&mut SourceFile> = inputs.iter_mut().filter(|f| f.is_audio).collect();
+    inputs_audio.sort_by_key(|f| f.original.clone().unwrap_or_else(|| PathBuf::from(&f.display)));
+    for (i, input) in inputs_audio.into_iter().enumerate() {
         if let Some(e) = targets_audio.get(i) {
             input.mapping = Some(e.index);
         } else {
@@ -109,7 +128,7 @@ fn map_by_size(inputs: &mut Vec<SourceFile>, targets: &Vec<TargetFile>) {
 }
 
 // manual adjust of the mapping
-fn map_manual(inputs: &mut Vec<SourceFile>, targets: &Vec<TargetFile>) {
+pub fn map_manual(inputs: &mut [SourceFile], targets: &[TargetFile]) {
     println!("Listing target files and their index:");
     for item in targets.iter().filter(|f| f.is_audio) {
         println!(" {} | {}", item.index, item.path.to_string_lossy());
@@ -0,0 +1,622 @@
+use ::migration::SourceFile;
+use migration::MigrationError;
+use migration::claxon::FlacReader;
+use migration::overlay;
+use migration::rayon::prelude::*;
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::ops::Range;
+use std::rc::Rc;
+
+// decoded FLAC input, the metadata is kept as raw bytes so it can be reused as is
+pub struct Decoded {
+    pub header: Vec<u8>,
+    streaminfo: usize, // position of the STREAMINFO block data within the header
+    sample_rate: u32,
+    bits_per_sample: u32,
+    channels: Vec<Vec<i32>>,
+    positions: Vec<(u64, u64)>, // how much of the input was read after each block and the samples decoded by then
+}
+
+impl Decoded {
+    // the first sample of the audio at a position in the input, estimated from how far the decoder had read
+    pub fn sample_at(&self, position:u64) -> u64 {
+        let before = self.positions.iter().take_while(|p| p.0 < position).last();
+        before.map_or(0, |p| p.1)
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.channels.first().map_or(0, |c| c.len() as u64)
+    }
+}
+
+// counts the bytes that are read from the input
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R:Read> Read for CountingReader<R> {
+    fn read(&mut self, buffer:&mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    Independent,
+    MidSide, // exhaustive search over the four stereo decorrelation modes
+}
+
+// the encoder only models the fixed predictor subset of the reference encoder (compression levels 0-2),
+// LPC output depends on floating point details that can't be reproduced reliably
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderSettings {
+    pub block_size: u32,
+    pub stereo: StereoMode,
+    pub max_partition_order: u32,
+}
+
+impl fmt::Display for EncoderSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "blocksize {}, {}, max partition order {}",
+            self.block_size,
+            if self.stereo == StereoMode::MidSide { "mid-side" } else { "independent" },
+            self.max_partition_order)
+    }
+}
+
+// vendor strings of common reference encoder releases, tried when the metadata doesn't match
+pub static VENDORS:&[&str] = &[
+    "reference libFLAC 1.4.3 20230623",
+    "reference libFLAC 1.4.2 20221022",
+    "reference libFLAC 1.3.4 20220220",
+    "reference libFLAC 1.3.3 20190804",
+    "reference libFLAC 1.3.2 20170101",
+    "reference libFLAC 1.3.1 20141125",
+    "reference libFLAC 1.3.0 20130526",
+    "reference libFLAC 1.2.1 20070917",
+];
+
+// the settings matrix to try, ordered so the most common configurations come first
+pub fn settings_matrix() -> Vec<EncoderSettings> {
+    let mut matrix = Vec::new();
+    for block_size in &[1152, 4096, 4608, 2048, 576, 8192] {
+        for stereo in &[StereoMode::MidSide, StereoMode::Independent] {
+            for max_partition_order in &[3, 4, 5, 6] {
+                matrix.push(EncoderSettings { block_size:*block_size, stereo:*stereo, max_partition_order:*max_partition_order });
+            }
+        }
+    }
+    matrix
+}
+
+// locate the end of the metadata blocks, returns the STREAMINFO data position and the header length
+fn parse_header(data:&[u8]) -> Option<(usize, usize)> {
+    let mut pos = 0;
+    // skip an ID3v2 tag if one is present
+    if data.len() >= 10 && &data[0..3] == b"ID3" {
+        let size = data[6..10].iter().fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f));
+        pos = 10 + size + if data[5] & 0x10 != 0 { 10 } else { 0 };
+    }
+    if data.len() < pos + 8 || &data[pos..pos+4] != b"fLaC" {
+        return None;
+    }
+    pos += 4;
+    let streaminfo = pos + 4;
+    loop {
+        if data.len() < pos + 4 {
+            return None;
+        }
+        let last = data[pos] & 0x80 != 0;
+        let length = ((data[pos+1] as usize) << 16) | ((data[pos+2] as usize) << 8) | data[pos+3] as usize;
+        pos += 4 + length;
+        if last {
+            break;
+        }
+    }
+    if pos > data.len() {
+        return None;
+    }
+    Some((streaminfo, pos))
+}
+
+// read the metadata blocks at the start of the input block by block, returns them as raw bytes
+// and the position of the STREAMINFO block data within them
+fn read_header<R:Read>(reader:&mut R) -> Result<(Vec<u8>, usize), MigrationError> {
+    let mut header = vec![0u8; 4];
+    reader.read_exact(&mut header)?;
+    // an ID3v2 tag is kept as part of the header
+    if &header[0..3] == b"ID3" {
+        header.resize(10, 0);
+        reader.read_exact(&mut header[4..10])?;
+        let size = header[6..10].iter().fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f));
+        let size = size + if header[5] & 0x10 != 0 { 10 } else { 0 };
+        reader.take(size as u64 + 4).read_to_end(&mut header)?;
+        if header.len() != 10 + size + 4 {
+            return Err("Not a valid FLAC file".into());
+        }
+    }
+    if &header[header.len()-4..] != b"fLaC" {
+        return Err("Not a valid FLAC file".into());
+    }
+    let streaminfo = header.len() + 4;
+    loop {
+        let position = header.len();
+        reader.take(4).read_to_end(&mut header)?;
+        if header.len() != position + 4 {
+            return Err("Not a valid FLAC file".into());
+        }
+        let last = header[position] & 0x80 != 0;
+        let length = ((header[position+1] as usize) << 16) | ((header[position+2] as usize) << 8) | header[position+3] as usize;
+        reader.take(length as u64).read_to_end(&mut header)?;
+        if header.len() != position + 4 + length {
+            return Err("Not a valid FLAC file".into());
+        }
+        if last {
+            break;
+        }
+    }
+    Ok((header, streaminfo))
+}
+
+// decode the input file to PCM, keeping the original metadata blocks
+pub fn decode(input:&SourceFile) -> Result<Decoded, MigrationError> {
+    let (header, streaminfo) = read_header(&mut overlay::open(input)?)?;
+
+    let count = Rc::new(Cell::new(0));
+    let mut reader = FlacReader::new(CountingReader { inner:overlay::open(input)?, count:count.clone() })?;
+    let info = reader.streaminfo();
+    let mut channels:Vec<Vec<i32>> = (0..info.channels).map(|_| Vec::with_capacity(info.samples.unwrap_or(0) as usize)).collect();
+    let mut positions = Vec::new();
+    {
+        let mut blocks = reader.blocks();
+        let mut buffer = Vec::new();
+        while let Some(block) = blocks.read_next_or_eof(buffer)? {
+            for (ch, channel) in channels.iter_mut().enumerate() {
+                channel.extend_from_slice(block.channel(ch as u32));
+            }
+            positions.push((count.get(), block.time() + block.duration() as u64));
+            buffer = block.into_buffer();
+        }
+    }
+    Ok(Decoded { header, streaminfo, sample_rate:info.sample_rate, bits_per_sample:info.bits_per_sample, channels, positions })
+}
+
+// replace the vendor string in the VORBIS_COMMENT block of a header, None if there is no such block
+pub fn with_vendor(header:&[u8], vendor:&str) -> Option<Vec<u8>> {
+    let (_, header_length) = parse_header(header)?;
+    let mut pos = 0;
+    while pos + 4 <= header_length && &header[pos..pos+4] != b"fLaC" {
+        pos += 1;
+    }
+    pos += 4;
+    while pos + 4 <= header_length {
+        let length = ((header[pos+1] as usize) << 16) | ((header[pos+2] as usize) << 8) | header[pos+3] as usize;
+        if header[pos] & 0x7f == 4 && length >= 4 {
+            let data = pos + 4;
+            let vendor_length = header[data] as usize | (header[data+1] as usize) << 8 | (header[data+2] as usize) << 16 | (header[data+3] as usize) << 24;
+            if data + 4 + vendor_length > data + length {
+                return None;
+            }
+            let new_length = length - vendor_length + vendor.len();
+            let mut result = header[0..pos].to_vec();
+            result.push(header[pos]);
+            result.extend_from_slice(&[(new_length >> 16) as u8, (new_length >> 8) as u8, new_length as u8]);
+            let vl = vendor.len() as u32;
+            result.extend_from_slice(&[vl as u8, (vl >> 8) as u8, (vl >> 16) as u8, (vl >> 24) as u8]);
+            result.extend_from_slice(vendor.as_bytes());
+            result.extend_from_slice(&header[data+4+vendor_length..]);
+            return Some(result);
+        }
+        if header[pos] & 0x80 != 0 {
+            break;
+        }
+        pos += 4 + length;
+    }
+    None
+}
+
+pub fn frame_count(decoded:&Decoded, settings:&EncoderSettings) -> usize {
+    let block_size = settings.block_size as u64;
+    decoded.samples().div_ceil(block_size) as usize
+}
+
+// encode a range of the frames of the decoded audio with the given settings
+pub fn encode_frames(decoded:&Decoded, settings:&EncoderSettings, frames:Range<usize>) -> Vec<Vec<u8>> {
+    let total = decoded.samples() as usize;
+    let block_size = settings.block_size as usize;
+    // frames are independent from each other so they can be encoded in parallel
+    frames.into_par_iter().map(|frame| {
+        let start = frame * block_size;
+        let end = ::std::cmp::min(start + block_size, total);
+        let block:Vec<&[i32]> = decoded.channels.iter().map(|c| &c[start..end]).collect();
+        encode_frame(frame as u64, &block, decoded, settings)
+    }).collect()
+}
+
+// put all encoded frames behind the input metadata
+pub fn assemble(decoded:&Decoded, settings:&EncoderSettings, frames:&[Vec<u8>]) -> Vec<u8> {
+    let block_size = settings.block_size as usize;
+    // update the block and frame sizes in STREAMINFO, the MD5 stays valid as the audio is the same
+    let mut output = decoded.header.clone();
+    let si = decoded.streaminfo;
+    let min_frame = frames.iter().map(|f| f.len()).min().unwrap_or(0);
+    let max_frame = frames.iter().map(|f| f.len()).max().unwrap_or(0);
+    output[si] = (block_size >> 8) as u8;
+    output[si+1] = block_size as u8;
+    output[si+2] = (block_size >> 8) as u8;
+    output[si+3] = block_size as u8;
+    output[si+4..si+7].copy_from_slice(&[(min_frame >> 16) as u8, (min_frame >> 8) as u8, min_frame as u8]);
+    output[si+7..si+10].copy_from_slice(&[(max_frame >> 16) as u8, (max_frame >> 8) as u8, max_frame as u8]);
+    for frame in frames {
+        output.extend_from_slice(frame);
+    }
+    output
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes:Vec::new(), acc:0, bits:0 }
+    }
+
+    // write the lowest n bits of value, n can be at most 32
+    fn write(&mut self, value:u64, n:u32) {
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1u64 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+        self.acc &= (1u64 << self.bits) - 1;
+    }
+
+    fn write_unary(&mut self, zeros:u32) {
+        let mut zeros = zeros;
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.write(0, pad);
+        }
+    }
+}
+
+fn crc8(data:&[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= *byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(data:&[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn block_size_code(size:u32) -> (u64, Option<(u64, u32)>) {
+    match size {
+        192 => (1, None),
+        576 | 1152 | 2304 | 4608 => (2 + (size / 576).trailing_zeros() as u64, None),
+        256 | 512 | 1024 | 2048 | 4096 | 8192 | 16384 | 32768 => (size.trailing_zeros() as u64, None),
+        s if s <= 256 => (6, Some((s as u64 - 1, 8))),
+        s => (7, Some((s as u64 - 1, 16))),
+    }
+}
+
+fn sample_rate_code(rate:u32) -> (u64, Option<(u64, u32)>) {
+    match rate {
+        88200 => (1, None),
+        176400 => (2, None),
+        192000 => (3, None),
+        8000 => (4, None),
+        16000 => (5, None),
+        22050 => (6, None),
+        24000 => (7, None),
+        32000 => (8, None),
+        44100 => (9, None),
+        48000 => (10, None),
+        96000 => (11, None),
+        r if r % 1000 == 0 && r / 1000 <= 255 => (12, Some((r as u64 / 1000, 8))),
+        r if r <= 65535 => (13, Some((r as u64, 16))),
+        r if r % 10 == 0 && r / 10 <= 65535 => (14, Some((r as u64 / 10, 16))),
+        _ => (0, None),
+    }
+}
+
+fn bits_per_sample_code(bps:u32) -> u64 {
+    match bps {
+        8 => 1,
+        12 => 2,
+        16 => 4,
+        20 => 5,
+        24 => 6,
+        32 => 7,
+        _ => 0,
+    }
+}
+
+// the frame number is coded with the extended UTF-8 scheme
+fn write_utf8(bw:&mut BitWriter, value:u64) {
+    if value < 0x80 {
+        bw.write(value, 8);
+        return;
+    }
+    let mut continuation = 1;
+    while value >= (1u64 << (5 * continuation + 6)) {
+        continuation += 1;
+    }
+    let lead_bits = 6 - continuation;
+    let lead = (0xffu64 << (7 - continuation)) & 0xff;
+    bw.write(lead | (value >> (6 * continuation)) & ((1 << lead_bits) - 1), 8);
+    for i in (0..continuation).rev() {
+        bw.write(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed { order:usize, partition_order:u32, parameters:Vec<u32> },
+}
+
+struct Subframe {
+    kind: SubframeKind,
+    wasted: u32,
+    bps: u32, // bits per sample after removing the wasted bits
+    samples: Vec<i32>,
+    bits: u64, // estimated size, this is what the reference encoder compares
+}
+
+fn encode_frame(number:u64, block:&[&[i32]], decoded:&Decoded, settings:&EncoderSettings) -> Vec<u8> {
+    let bps = decoded.bits_per_sample;
+    let len = block[0].len();
+    let rice_limit = if bps > 16 { 31 } else { 15 };
+
+    // pick the channel assignment and subframes
+    let (assignment, subframes) = if block.len() == 2 && settings.stereo == StereoMode::MidSide {
+        let mid:Vec<i32> = block[0].iter().zip(block[1].iter()).map(|(l, r)| (l + r) >> 1).collect();
+        let side:Vec<i32> = block[0].iter().zip(block[1].iter()).map(|(l, r)| l - r).collect();
+        let left = plan_subframe(block[0], bps, settings, rice_limit);
+        let right = plan_subframe(block[1], bps, settings, rice_limit);
+        let mid = plan_subframe(&mid, bps, settings, rice_limit);
+        let side = plan_subframe(&side, bps + 1, settings, rice_limit);
+        let costs = [left.bits + right.bits, left.bits + side.bits, right.bits + side.bits, mid.bits + side.bits];
+        let mut best = 0;
+        for i in 1..4 {
+            if costs[i] < costs[best] {
+                best = i;
+            }
+        }
+        match best {
+            0 => (1, vec![left, right]),
+            1 => (8, vec![left, side]),
+            2 => (9, vec![side, right]),
+            _ => (10, vec![mid, side]),
+        }
+    } else {
+        (block.len() as u64 - 1, block.iter().map(|c| plan_subframe(c, bps, settings, rice_limit)).collect())
+    };
+
+    // frame header
+    let mut bw = BitWriter::new();
+    bw.write(0xfff8, 16);
+    // a shorter last block gets its size coded explicitly
+    let (bs_code, bs_extra) = block_size_code(len as u32);
+    let (sr_code, sr_extra) = sample_rate_code(decoded.sample_rate);
+    bw.write(bs_code, 4);
+    bw.write(sr_code, 4);
+    bw.write(assignment, 4);
+    bw.write(bits_per_sample_code(bps), 3);
+    bw.write(0, 1);
+    write_utf8(&mut bw, number);
+    if let Some((value, n)) = bs_extra {
+        bw.write(value, n);
+    }
+    if let Some((value, n)) = sr_extra {
+        bw.write(value, n);
+    }
+    let crc = crc8(&bw.bytes);
+    bw.write(crc as u64, 8);
+
+    // subframes
+    for subframe in &subframes {
+        write_subframe(&mut bw, subframe);
+    }
+    bw.align();
+    let crc = crc16(&bw.bytes);
+    bw.write(crc as u64, 16);
+    bw.bytes
+}
+
+// sums of the absolute residuals of each fixed predictor order, as the reference encoder computes them
+fn fixed_errors(data:&[i32]) -> [u64; 5] {
+    let mut errors = [0u64; 5];
+    for i in 4..data.len() {
+        let e0 = data[i] as i64;
+        let e1 = e0 - data[i-1] as i64;
+        let e2 = e1 - (data[i-1] as i64 - data[i-2] as i64);
+        let e3 = e2 - (data[i-1] as i64 - 2 * data[i-2] as i64 + data[i-3] as i64);
+        let e4 = e3 - (data[i-1] as i64 - 3 * data[i-2] as i64 + 3 * data[i-3] as i64 - data[i-4] as i64);
+        errors[0] += e0.unsigned_abs();
+        errors[1] += e1.unsigned_abs();
+        errors[2] += e2.unsigned_abs();
+        errors[3] += e3.unsigned_abs();
+        errors[4] += e4.unsigned_abs();
+    }
+    errors
+}
+
+fn best_fixed_order(errors:&[u64; 5]) -> usize {
+    // ties go to the higher order, matching the reference comparison chain
+    let min = |a:&[u64]| a.iter().cloned().min().unwrap();
+    if errors[0] < min(&errors[1..]) { 0 }
+    else if errors[1] < min(&errors[2..]) { 1 }
+    else if errors[2] < min(&errors[3..]) { 2 }
+    else if errors[3] < errors[4] { 3 }
+    else { 4 }
+}
+
+fn fixed_residual(data:&[i32], order:usize) -> Vec<i32> {
+    (order..data.len()).map(|i| {
+        let prediction = match order {
+            0 => 0,
+            1 => data[i-1] as i64,
+            2 => 2 * data[i-1] as i64 - data[i-2] as i64,
+            3 => 3 * data[i-1] as i64 - 3 * data[i-2] as i64 + data[i-3] as i64,
+            _ => 4 * data[i-1] as i64 - 6 * data[i-2] as i64 + 4 * data[i-3] as i64 - data[i-4] as i64,
+        };
+        (data[i] as i64 - prediction) as i32
+    }).collect()
+}
+
+fn rice_bits(parameter:u32, samples:u64, sum:u64) -> u64 {
+    let magnitude = if parameter > 0 { sum >> (parameter - 1) } else { sum << 1 };
+    (4 + (1 + parameter as u64) * samples + magnitude).saturating_sub(samples >> 1)
+}
+
+// choose the partition order and rice parameters, returns (bits, partition order, parameters)
+fn partition_residual(residual:&[i32], block_size:usize, order:usize, max_order:u32, rice_limit:u32) -> (u64, u32, Vec<u32>) {
+    // the partition count has to divide the block size and each partition must be longer than the predictor order
+    let mut max_order = ::std::cmp::min(max_order, (block_size as u32).trailing_zeros());
+    while max_order > 0 && (block_size >> max_order) <= order {
+        max_order -= 1;
+    }
+    let mut best:Option<(u64, u32, Vec<u32>)> = None;
+    for partition_order in (0..max_order + 1).rev() {
+        let partitions = 1usize << partition_order;
+        let default_samples = block_size >> partition_order;
+        let mut bits = 6;
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut position = 0;
+        for partition in 0..partitions {
+            let samples = if partition == 0 { default_samples - order } else { default_samples };
+            let sum:u64 = residual[position..position+samples].iter().map(|r| (*r as i64).unsigned_abs()).sum();
+            position += samples;
+            let mut parameter = 0;
+            let mut k = samples as u64;
+            while k < sum {
+                parameter += 1;
+                k <<= 1;
+            }
+            if parameter >= rice_limit {
+                parameter = rice_limit - 1;
+            }
+            bits += rice_bits(parameter, samples as u64, sum);
+            parameters.push(parameter);
+        }
+        let better = match best {
+            Some((b, _, _)) => bits < b,
+            None => true,
+        };
+        if better {
+            best = Some((bits, partition_order, parameters));
+        }
+    }
+    best.unwrap()
+}
+
+fn plan_subframe(samples:&[i32], bps:u32, settings:&EncoderSettings, rice_limit:u32) -> Subframe {
+    // shift out the bits that are zero in every sample
+    let or = samples.iter().fold(0i32, |acc, s| acc | *s);
+    let wasted = if or == 0 { 0 } else { or.trailing_zeros() };
+    let samples:Vec<i32> = if wasted > 0 { samples.iter().map(|s| s >> wasted).collect() } else { samples.to_vec() };
+    let bps = bps - wasted;
+    let header_bits = 8 + wasted as u64;
+
+    let mut subframe = Subframe { kind:SubframeKind::Verbatim, wasted, bps, bits:header_bits + samples.len() as u64 * bps as u64, samples };
+    if subframe.samples.len() > 4 {
+        let errors = fixed_errors(&subframe.samples);
+        if errors[1] == 0 && subframe.samples.iter().all(|s| *s == subframe.samples[0]) {
+            subframe.kind = SubframeKind::Constant;
+            subframe.bits = header_bits + bps as u64;
+            return subframe;
+        }
+        let order = best_fixed_order(&errors);
+        let residual = fixed_residual(&subframe.samples, order);
+        let (residual_bits, partition_order, parameters) = partition_residual(&residual, subframe.samples.len(), order, settings.max_partition_order, rice_limit);
+        let bits = header_bits + order as u64 * bps as u64 + residual_bits;
+        if bits < subframe.bits {
+            subframe.kind = SubframeKind::Fixed { order, partition_order, parameters };
+            subframe.bits = bits;
+        }
+    }
+    subframe
+}
+
+fn write_subframe(bw:&mut BitWriter, subframe:&Subframe) {
+    let type_bits = match subframe.kind {
+        SubframeKind::Constant => 0,
+        SubframeKind::Verbatim => 1,
+        SubframeKind::Fixed { order, .. } => 8 | order as u64,
+    };
+    bw.write(type_bits, 7);
+    if subframe.wasted > 0 {
+        bw.write(1, 1);
+        bw.write_unary(subframe.wasted - 1);
+    } else {
+        bw.write(0, 1);
+    }
+    let bps = subframe.bps;
+    match subframe.kind {
+        SubframeKind::Constant => {
+            bw.write(subframe.samples[0] as u64, bps);
+        },
+        SubframeKind::Verbatim => {
+            for sample in &subframe.samples {
+                bw.write(*sample as u64, bps);
+            }
+        },
+        SubframeKind::Fixed { order, partition_order, ref parameters } => {
+            for sample in &subframe.samples[0..order] {
+                bw.write(*sample as u64, bps);
+            }
+            let residual = fixed_residual(&subframe.samples, order);
+            let rice2 = parameters.iter().any(|p| *p >= 15);
+            let parameter_bits = if rice2 { 5 } else { 4 };
+            bw.write(if rice2 { 1 } else { 0 }, 2);
+            bw.write(partition_order as u64, 4);
+            let default_samples = subframe.samples.len() >> partition_order;
+            let mut position = 0;
+            for (partition, parameter) in parameters.iter().enumerate() {
+                let samples = if partition == 0 { default_samples - order } else { default_samples };
+                bw.write(*parameter as u64, parameter_bits);
+                for r in &residual[position..position+samples] {
+                    let folded = ((*r << 1) ^ (*r >> 31)) as u32;
+                    bw.write_unary(folded >> parameter);
+                    bw.write(folded as u64, *parameter);
+                }
+                position += samples;
+            }
+        },
+    }
+}
@@ -0,0 +1,224 @@
+use ::migration::SourceFile;
+use migration::MigrationError;
+use migration::overlay;
+use std::io::Read;
+use std::ops::Range;
+
+// raw audio elementary streams, these are a plain sequence of frames that each start with a sync word
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Adts,
+    Ac3,
+    Dts,
+}
+
+// AC-3 bitrates in kbit/s, indexed by half the frame size code
+static AC3_BITRATES:&[usize] = &[32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640];
+
+impl Stream {
+    pub fn from_extension(extension:&str) -> Option<Stream> {
+        match extension {
+            "aac" => Some(Stream::Adts),
+            "ac3" => Some(Stream::Ac3),
+            "dts" => Some(Stream::Dts),
+            _ => None,
+        }
+    }
+
+    // the length of the frame starting at the start of the data, None when there is no valid frame header
+    fn frame_length(&self, data:&[u8]) -> Option<usize> {
+        let length = match *self {
+            Stream::Adts => {
+                // 12 sync bits and a layer of 0, the sampling frequency index has 3 reserved values
+                if data.len() < 7 || data[0] != 0xff || data[1] & 0xf6 != 0xf0 || (data[2] >> 2) & 0x0f > 12 {
+                    return None;
+                }
+                ((data[3] as usize & 0x03) << 11) | ((data[4] as usize) << 3) | (data[5] as usize >> 5)
+            },
+            Stream::Ac3 => {
+                if data.len() < 6 || data[0] != 0x0b || data[1] != 0x77 {
+                    return None;
+                }
+                let bsid = data[5] >> 3;
+                if bsid > 10 && bsid <= 16 {
+                    // E-AC-3 stores the frame size directly
+                    ((((data[2] as usize) & 0x07) << 8) | data[3] as usize) * 2 + 2
+                } else if bsid <= 8 {
+                    let code = data[4] as usize & 0x3f;
+                    let bitrate = *AC3_BITRATES.get(code / 2)?;
+                    let words = match data[4] >> 6 {
+                        0 => bitrate * 2,
+                        1 => bitrate * 96000 / 44100 + (code & 1),
+                        2 => bitrate * 3,
+                        _ => return None,
+                    };
+                    words * 2
+                } else {
+                    return None;
+                }
+            },
+            Stream::Dts => {
+                // the core sync word in 16 bit big endian form
+                if data.len() < 10 || data[0..4] != [0x7f, 0xfe, 0x80, 0x01] {
+                    return None;
+                }
+                let size = ((data[5] as usize & 0x03) << 12) | ((data[6] as usize) << 4) | (data[7] as usize >> 4);
+                if size < 95 {
+                    return None;
+                }
+                size + 1
+            },
+        };
+        if length < 7 { None } else { Some(length) }
+    }
+}
+
+// frames of all formats are shorter than this, the scan keeps a frame and the header behind it in memory
+const MAX_FRAME:usize = 1 << 15;
+const CHUNK:u64 = 1 << 20;
+
+// find the frames in a file, a sync word can appear in the audio data by chance so a frame only counts
+// when it directly follows the previous frame or is directly followed by another frame
+// the file is read in chunks, only the data around the current position is kept
+pub fn scan(input:&SourceFile, stream:Stream) -> Result<Vec<Range<u64>>, MigrationError> {
+    let mut file = overlay::open(input)?;
+    let mut frames:Vec<Range<u64>> = Vec::new();
+    let mut buffer = Vec::new();
+    let mut base = 0u64; // where the buffer starts in the file
+    let mut position = 0;
+    let mut end_of_file = false;
+    loop {
+        if !end_of_file && buffer.len() - position < 2 * MAX_FRAME {
+            buffer.drain(..position);
+            base += position as u64;
+            position = 0;
+            end_of_file = (&mut file).take(CHUNK).read_to_end(&mut buffer)? == 0;
+            continue;
+        }
+        if position + 4 > buffer.len() {
+            break;
+        }
+        // the end of the data is only reached at the end of the file, the buffer holds at least two frames otherwise
+        let data = &buffer[position..];
+        if let Some(length) = stream.frame_length(data) {
+            let start = base + position as u64;
+            let chained = frames.last().is_some_and(|f| f.end == start);
+            if length <= data.len() && (chained || length == data.len() || stream.frame_length(&data[length..]).is_some()) {
+                frames.push(start..start + length as u64);
+                position += length;
+                continue;
+            }
+        }
+        position += 1;
+    }
+    Ok(frames)
+}
+
+// offsets that assume the target holds the same frames behind a header of its own, the header length is
+// derived from the target size without a trailer or with an ID3v1 tag, or the frames start the file
+pub fn header_offsets(frames:&[Range<u64>], target_size:u64) -> Vec<i64> {
+    let (first, last) = match (frames.first(), frames.last()) {
+        (Some(f), Some(l)) => (f.start as i64, l.end as i64),
+        _ => return Vec::new(),
+    };
+    let length = last - first;
+    let mut offsets:Vec<i64> = [0, target_size as i64 - length, target_size as i64 - length - 128].iter()
+        .filter(|h| **h >= 0).map(|h| first - h).collect();
+    offsets.dedup();
+    offsets
+}
+
+// the offsets that put an input frame where the target implies a frame starts, the target starts with a frame
+// either at its beginning or behind a header as long as the one of the input, frames may be missing before it
+// the offsets of the same frames behind a header derived from the target size are included
+pub fn aligned_offsets(frames:&[Range<u64>], target_size:u64) -> Vec<i64> {
+    let first = match frames.first() {
+        Some(f) => f.start as i64,
+        None => return Vec::new(),
+    };
+    let mut offsets = header_offsets(frames, target_size);
+    for frame in frames {
+        offsets.push(frame.start as i64);
+        offsets.push(frame.start as i64 - first);
+    }
+    offsets.sort();
+    offsets.dedup();
+    offsets
+}
+#[cfg(test)]
+mod tests {
+    use ::migration::SourceFile;
+    use super::{Stream, aligned_offsets, scan};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    // an ADTS frame of the given length, the rest of the header and the audio data are zero
+    fn adts_frame(length:usize) -> Vec<u8> {
+        let mut frame = vec![0u8; length];
+        frame[..6].copy_from_slice(&[0xff, 0xf1, 0x50, (length >> 11) as u8 & 0x03, (length >> 3) as u8, (length << 5) as u8 | 0x1f]);
+        frame
+    }
+
+    #[test]
+    fn adts_frame_length() {
+        assert_eq!(Stream::Adts.frame_length(&adts_frame(371)), Some(371));
+        assert_eq!(Stream::Adts.frame_length(&adts_frame(6000)), Some(6000));
+        // reserved sampling frequency, layer other than 0, too short for a header
+        assert_eq!(Stream::Adts.frame_length(&[0xff, 0xf1, 0x34, 0x00, 0x2e, 0x7f, 0x00]), None);
+        assert_eq!(Stream::Adts.frame_length(&[0xff, 0xf3, 0x50, 0x00, 0x2e, 0x7f, 0x00]), None);
+        assert_eq!(Stream::Adts.frame_length(&[0xff, 0xf1, 0x50, 0x00, 0x2e]), None);
+    }
+
+    #[test]
+    fn ac3_frame_length() {
+        // 64 kbit/s at 48, 44.1 (odd frame size code) and 32 kHz
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0, 0, 0x08, 0x40]), Some(256));
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0, 0, 0x49, 0x40]), Some(280));
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0, 0, 0x88, 0x40]), Some(384));
+        // reserved sample rate and frame size code
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0, 0, 0xc8, 0x40]), None);
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0, 0, 0x26, 0x40]), None);
+        // E-AC-3 and an unknown bitstream id
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0x01, 0xff, 0x00, 0x80]), Some(1024));
+        assert_eq!(Stream::Ac3.frame_length(&[0x0b, 0x77, 0, 0, 0x08, 0x48]), None);
+    }
+
+    #[test]
+    fn dts_frame_length() {
+        assert_eq!(Stream::Dts.frame_length(&[0x7f, 0xfe, 0x80, 0x01, 0, 0x00, 0x7d, 0xc0, 0, 0]), Some(2013));
+        assert_eq!(Stream::Dts.frame_length(&[0x7f, 0xfe, 0x80, 0x01, 0, 0x00, 0x05, 0xe0, 0, 0]), None);
+        assert_eq!(Stream::Dts.frame_length(&[0x7f, 0xfe, 0x80, 0x00, 0, 0x00, 0x7d, 0xc0, 0, 0]), None);
+    }
+
+    #[test]
+    fn scan_skips_sync_words_in_other_data() {
+        // a sync word in the data before the frames and a short trailer behind them
+        let mut data = vec![0x00, 0xff, 0xf1, 0x50, 0x80];
+        for &length in &[20, 30, 25] {
+            data.extend(adts_frame(length));
+        }
+        data.extend(&[0x54, 0x41, 0x47]);
+        let path = env::temp_dir().join(format!("mtmigrate-test-frames-{}", process::id()));
+        fs::write(&path, &data).unwrap();
+        let input = SourceFile {
+            path: path.clone(),
+            display: "input".to_string(),
+            extension: Some("aac".to_string()),
+            is_audio: true,
+            size: data.len() as u64,
+            mapping: None,
+            derived: false,
+            segments: None,
+            original: None,
+            verified: None,
+        };
+        let frames = scan(&input, Stream::Adts).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(frames, vec![5..25, 25..55, 55..80]);
+
+        // the same frames as the target, behind no header or a header of 10 bytes with an ID3v1 tag
+        assert_eq!(aligned_offsets(&frames, 75), vec![0, 5, 20, 25, 50, 55]);
+        assert_eq!(aligned_offsets(&frames, 213), vec![-133, -5, 0, 5, 20, 25, 50, 55]);
+    }
+}
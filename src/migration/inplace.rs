@@ -0,0 +1,604 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::migrator;
+use std::cmp::{min, max};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// the journal and everything it needs to roll back live in this directory inside the input
+static JOURNAL_DIR:&str = ".mtmigrate";
+static JOURNAL_HEADER:&str = "mtmigrate journal 1";
+
+// the most data that is moved in one go, every chunk goes through the journal
+const CHUNK_SIZE:u64 = 8 << 20;
+
+// one step of an in-place migration, paths are relative to the input directory
+// every step can be repeated after an interruption, data moves are resumed chunk by chunk
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Backup { file:String, start:u64, end:u64, backup:String }, // save bytes that are about to be overwritten or cut off
+    Restore { file:String, start:u64, backup:String },
+    Copy { from:String, to:String },
+    Rename { from:String, to:String },
+    Resize { file:String, from:u64, to:u64 },
+    Move { file:String, from:u64, to:u64, length:u64 }, // shift data inside a file
+    Zero { file:String, start:u64, end:u64 },
+    Create { file:String, size:u64 },
+    Remove { file:String },
+}
+
+impl Step {
+    fn to_line(&self) -> String {
+        match *self {
+            Step::Backup { ref file, start, end, ref backup } => format!("backup\t{}\t{}\t{}\t{}", file, start, end, backup),
+            Step::Restore { ref file, start, ref backup } => format!("restore\t{}\t{}\t{}", file, start, backup),
+            Step::Copy { ref from, ref to } => format!("copy\t{}\t{}", from, to),
+            Step::Rename { ref from, ref to } => format!("rename\t{}\t{}", from, to),
+            Step::Resize { ref file, from, to } => format!("resize\t{}\t{}\t{}", file, from, to),
+            Step::Move { ref file, from, to, length } => format!("move\t{}\t{}\t{}\t{}", file, from, to, length),
+            Step::Zero { ref file, start, end } => format!("zero\t{}\t{}\t{}", file, start, end),
+            Step::Create { ref file, size } => format!("create\t{}\t{}", file, size),
+            Step::Remove { ref file } => format!("remove\t{}", file),
+        }
+    }
+
+    fn parse(line:&str) -> Option<Step> {
+        let fields:Vec<&str> = line.split('\t').collect();
+        let text = |i:usize| fields.get(i).map(|f| f.to_string());
+        let number = |i:usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+        let step = match (fields[0], fields.len()) {
+            ("backup", 5) => Step::Backup { file:text(1)?, start:number(2)?, end:number(3)?, backup:text(4)? },
+            ("restore", 4) => Step::Restore { file:text(1)?, start:number(2)?, backup:text(3)? },
+            ("copy", 3) => Step::Copy { from:text(1)?, to:text(2)? },
+            ("rename", 3) => Step::Rename { from:text(1)?, to:text(2)? },
+            ("resize", 4) => Step::Resize { file:text(1)?, from:number(2)?, to:number(3)? },
+            ("move", 5) => Step::Move { file:text(1)?, from:number(2)?, to:number(3)?, length:number(4)? },
+            ("zero", 4) => Step::Zero { file:text(1)?, start:number(2)?, end:number(3)? },
+            ("create", 3) => Step::Create { file:text(1)?, size:number(2)? },
+            ("remove", 2) => Step::Remove { file:text(1)? },
+            _ => return None,
+        };
+        Some(step)
+    }
+}
+
+// the state of a journal: its steps and how far it got
+struct Journal {
+    path: PathBuf,
+    file: File,
+    rollback: bool, // the steps undo an earlier migration
+    steps: Vec<Step>,
+    done: usize, // the steps before this one are complete
+    moved: u64, // bytes of the current step moved so far when it is a move
+    pending: Option<(u64, u64)>, // a buffered chunk of the current move that may not be written completely
+}
+
+impl Journal {
+    // write a new journal, it only replaces an existing one once it is complete
+    fn create(root:&Path, steps:Vec<Step>, rollback:bool) -> io::Result<Journal> {
+        let path = journal_path(root);
+        let temporary = path.with_extension("new");
+        {
+            let mut file = File::create(&temporary)?;
+            writeln!(file, "{}", JOURNAL_HEADER)?;
+            writeln!(file, "mode\t{}", if rollback { "rollback" } else { "migrate" })?;
+            for step in steps.iter() {
+                writeln!(file, "step\t{}", step.to_line())?;
+            }
+            writeln!(file, "begin")?;
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, &path)?;
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Journal { path, file, rollback, steps, done:0, moved:0, pending:None })
+    }
+
+    // read a journal left by an interrupted run, a journal that wasn't completely written was never started
+    fn open(root:&Path) -> io::Result<Option<Journal>> {
+        let path = journal_path(root);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "damaged journal");
+        let reader = BufReader::new(File::open(&path)?);
+        let mut lines = Vec::new();
+        for line in reader.lines() {
+            lines.push(line?);
+        }
+        if lines.first().map(|l| l.as_str()) != Some(JOURNAL_HEADER) {
+            return Err(invalid());
+        }
+        let rollback = lines.get(1).map(|l| l.as_str()) == Some("mode\trollback");
+        let mut steps = Vec::new();
+        let mut started = false;
+        let (mut done, mut moved, mut pending) = (0, 0, None);
+        for line in lines.iter().skip(2) {
+            if !started {
+                if line == "begin" {
+                    started = true;
+                } else if let Some(step) = line.strip_prefix("step\t") {
+                    steps.push(Step::parse(step).ok_or_else(invalid)?);
+                } else {
+                    return Err(invalid());
+                }
+                continue;
+            }
+            // a line cut off by the interruption is ignored, the step it records is repeated
+            let fields:Vec<u64> = match line.split(' ').skip(1).map(|f| f.parse::<u64>()).collect() {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
+            match (line.split(' ').next().unwrap(), fields.len()) {
+                ("done", 1) if fields[0] as usize == done => {
+                    done += 1;
+                    moved = 0;
+                    pending = None;
+                },
+                ("moved", 2) if fields[0] as usize == done => {
+                    moved = fields[1];
+                    pending = None;
+                },
+                ("chunk", 3) if fields[0] as usize == done => pending = Some((fields[1], fields[2])),
+                _ => {}
+            }
+        }
+        if !started {
+            return Ok(None);
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Some(Journal { path, file, rollback, steps, done, moved, pending }))
+    }
+
+    // the entry only counts once it is on disk
+    fn record(&mut self, entry:String) -> io::Result<()> {
+        writeln!(self.file, "{}", entry)?;
+        self.file.sync_data()
+    }
+}
+
+fn journal_dir(root:&Path) -> PathBuf {
+    root.join(JOURNAL_DIR)
+}
+
+fn journal_path(root:&Path) -> PathBuf {
+    journal_dir(root).join("journal")
+}
+
+// whether an interrupted in-place migration is waiting in the input directory
+pub fn has_journal(root:&Path) -> bool {
+    journal_dir(root).exists()
+}
+
+// the part of a data move a chunk covers, moves towards the start go front to back and moves towards the end
+// back to front, so the data that is still to be moved is never overwritten
+fn chunk_start(from:u64, to:u64, length:u64, done:u64, chunk:u64) -> u64 {
+    if to < from { done } else { length - done - chunk }
+}
+
+// write a chunk that was buffered in the journal directory before it was interrupted
+fn replay_chunk(root:&Path, journal:&mut Journal) -> io::Result<()> {
+    let (done, chunk) = match journal.pending {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if let Step::Move { ref file, from, to, length } = journal.steps[journal.done] {
+        let mut data = Vec::new();
+        File::open(journal_dir(root).join("chunk"))?.read_to_end(&mut data)?;
+        if data.len() as u64 != chunk {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "damaged chunk buffer"));
+        }
+        let mut handle = OpenOptions::new().write(true).open(root.join(file))?;
+        handle.seek(SeekFrom::Start(to + chunk_start(from, to, length, done, chunk)))?;
+        handle.write_all(&data)?;
+        handle.sync_data()?;
+    }
+    journal.record(format!("moved {} {}", journal.done, done + chunk))?;
+    journal.moved = done + chunk;
+    journal.pending = None;
+    Ok(())
+}
+
+// shift data inside a file, every chunk is buffered in the journal directory before it overwrites anything,
+// so an interrupted chunk can always be written again
+fn move_data(root:&Path, journal:&mut Journal, file:&str, from:u64, to:u64, length:u64) -> io::Result<()> {
+    if from == to {
+        return Ok(());
+    }
+    let mut handle = OpenOptions::new().read(true).write(true).open(root.join(file))?;
+    let buffer_path = journal_dir(root).join("chunk");
+    let mut done = journal.moved;
+    let mut data = Vec::new();
+    while done < length {
+        let chunk = min(CHUNK_SIZE, length - done);
+        let start = chunk_start(from, to, length, done, chunk);
+        data.resize(chunk as usize, 0);
+        handle.seek(SeekFrom::Start(from + start))?;
+        handle.read_exact(&mut data)?;
+        {
+            let mut buffer = File::create(&buffer_path)?;
+            buffer.write_all(&data)?;
+            buffer.sync_all()?;
+        }
+        journal.record(format!("chunk {} {} {}", journal.done, done, chunk))?;
+        handle.seek(SeekFrom::Start(to + start))?;
+        handle.write_all(&data)?;
+        handle.sync_data()?;
+        done += chunk;
+        journal.record(format!("moved {} {}", journal.done, done))?;
+        journal.moved = done;
+        print!("\r  {}: {:.1}/{:.1} MB moved\x1b[K", file, done as f64 / 1e6, length as f64 / 1e6);
+        io::stdout().flush().ok();
+    }
+    println!();
+    Ok(())
+}
+
+fn open_for_writing(path:&Path) -> io::Result<File> {
+    OpenOptions::new().write(true).open(path)
+}
+
+// run one step that isn't a data move, running it again has the same result
+fn run_step(root:&Path, step:&Step) -> io::Result<()> {
+    match *step {
+        Step::Backup { ref file, start, end, ref backup } => {
+            let mut source = File::open(root.join(file))?;
+            source.seek(SeekFrom::Start(start))?;
+            let mut destination = File::create(root.join(backup))?;
+            io::copy(&mut source.take(end - start), &mut destination)?;
+            destination.sync_all()?;
+        },
+        Step::Restore { ref file, start, ref backup } => {
+            let mut destination = open_for_writing(&root.join(file))?;
+            destination.seek(SeekFrom::Start(start))?;
+            io::copy(&mut File::open(root.join(backup))?, &mut destination)?;
+            destination.sync_data()?;
+        },
+        Step::Copy { ref from, ref to } => {
+            fs::copy(root.join(from), root.join(to))?;
+            File::open(root.join(to))?.sync_all()?;
+        },
+        Step::Rename { ref from, ref to } => {
+            let (from, to) = (root.join(from), root.join(to));
+            // a rename that already happened before the interruption is done
+            if from.exists() || !to.exists() {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(&from, &to)?;
+                println!("  {} => {}", from.strip_prefix(root).unwrap().display(), to.strip_prefix(root).unwrap().display());
+            }
+        },
+        Step::Resize { ref file, to, .. } => {
+            let handle = open_for_writing(&root.join(file))?;
+            handle.set_len(to)?;
+            handle.sync_all()?;
+        },
+        Step::Zero { ref file, start, end } => {
+            let mut handle = open_for_writing(&root.join(file))?;
+            handle.seek(SeekFrom::Start(start))?;
+            io::copy(&mut io::repeat(0).take(end - start), &mut handle)?;
+            handle.sync_data()?;
+        },
+        Step::Create { ref file, size } => {
+            let path = root.join(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = File::create(&path)?;
+            handle.set_len(size)?;
+            handle.sync_all()?;
+        },
+        Step::Remove { ref file } => {
+            if let Err(e) = fs::remove_file(root.join(file)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        },
+        Step::Move { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+// run the steps of a journal from where it got, the journal directory is removed when all steps are done
+fn execute(root:&Path, journal:&mut Journal) -> io::Result<()> {
+    replay_chunk(root, journal)?;
+    while journal.done < journal.steps.len() {
+        let step = journal.steps[journal.done].clone();
+        match step {
+            Step::Move { ref file, from, to, length } => move_data(root, journal, file, from, to, length)?,
+            ref other => run_step(root, other)?,
+        }
+        journal.record(format!("done {}", journal.done))?;
+        journal.done += 1;
+        journal.moved = 0;
+    }
+    fs::remove_dir_all(journal_dir(root))
+}
+
+// the steps that undo the steps a journal got through, in reverse order
+fn inverse_steps(root:&Path, journal:&Journal) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let last = min(journal.done + 1, journal.steps.len());
+    for index in (0..last).rev() {
+        let complete = index < journal.done;
+        match journal.steps[index] {
+            // a backup that didn't complete wasn't needed yet, nothing was changed after it
+            Step::Backup { ref file, start, ref backup, .. } => if complete {
+                steps.push(Step::Restore { file:file.clone(), start, backup:backup.clone() });
+            },
+            Step::Copy { ref to, .. } => steps.push(Step::Remove { file:to.clone() }),
+            Step::Rename { ref from, ref to } => {
+                if complete || (!root.join(from).exists() && root.join(to).exists()) {
+                    steps.push(Step::Rename { from:to.clone(), to:from.clone() });
+                }
+            },
+            Step::Resize { ref file, from, to } => steps.push(Step::Resize { file:file.clone(), from:to, to:from }),
+            Step::Move { ref file, from, to, length } => {
+                let moved = if complete { length } else { journal.moved };
+                if moved > 0 {
+                    let start = if to < from { 0 } else { length - moved };
+                    steps.push(Step::Move { file:file.clone(), from:to + start, to:from + start, length:moved });
+                }
+            },
+            Step::Create { ref file, .. } => steps.push(Step::Remove { file:file.clone() }),
+            // zeroed bytes come back with the backups, removed files were only temporary
+            Step::Restore { .. } | Step::Zero { .. } | Step::Remove { .. } => {}
+        }
+    }
+    steps
+}
+
+// the name an unused input gets when it is in the way of a target
+fn old_name(path:&str) -> String {
+    format!("{}.old", path)
+}
+
+// paths are stored as text in the journal
+fn journal_name(path:&Path) -> Option<String> {
+    let name = path.to_str()?;
+    if name.contains('\t') || name.contains('\n') {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+// the steps that turn the input directory into the target layout:
+// first the bytes that get lost are backed up and the mapped inputs are moved out of the way, then each
+// input is renamed to its target and its data is shifted by the offset, padded and truncated to the target size
+fn plan_steps(root:&Path, inputs:&[SourceFile], targets:&[TargetFile]) -> Result<Vec<Step>, String> {
+    let relative = |path:&Path| -> Result<String, String> {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        journal_name(path).ok_or_else(|| format!("'{}' can't be stored in the journal", path.display()))
+    };
+    let mut copies = Vec::new();
+    let mut staging = Vec::new();
+    let mut placing = Vec::new();
+    for target in targets {
+        let target_path = relative(&target.path)?;
+        let stage = format!("{}/{}", JOURNAL_DIR, target.index);
+        // an input that isn't used but is in the way of a target, like the original of a repaired file, is renamed
+        let occupant = root.join(&target.path);
+        if occupant.exists() && !inputs.iter().any(|i| i.mapping.is_some() && i.path == occupant) {
+            if !inputs.iter().any(|i| i.path == occupant) || root.join(old_name(&target_path)).exists() {
+                return Err(format!("'{}' is in the way of a target file", target.path.display()));
+            }
+            staging.push(Step::Rename { from:target_path.clone(), to:old_name(&target_path) });
+        }
+        let input = match target.mapping {
+            Some(m) => &inputs[m],
+            None => {
+                placing.push(Step::Create { file:target_path, size:target.size });
+                continue;
+            }
+        };
+        let (source_start, lead, length) = migrator::copy_range(input.size, target);
+        if input.derived {
+            // transformed data only exists in a temporary file, it is copied in before anything changes
+            let from = journal_name(&input.path).ok_or_else(|| format!("'{}' can't be stored in the journal", input.path.display()))?;
+            copies.push(Step::Copy { from, to:stage.clone() });
+        } else {
+            let input_path = relative(&input.path)?;
+            if source_start > 0 {
+                staging.push(Step::Backup { file:input_path.clone(), start:0, end:source_start, backup:format!("{}.head", stage) });
+            }
+            if source_start + length < input.size {
+                staging.push(Step::Backup { file:input_path.clone(), start:source_start + length, end:input.size, backup:format!("{}.tail", stage) });
+            }
+            staging.push(Step::Rename { from:input_path, to:stage.clone() });
+        }
+        placing.push(Step::Rename { from:stage, to:target_path.clone() });
+        let extended = max(input.size, lead + length);
+        if extended > input.size {
+            placing.push(Step::Resize { file:target_path.clone(), from:input.size, to:extended });
+        }
+        placing.push(Step::Move { file:target_path.clone(), from:source_start, to:lead, length });
+        if lead > 0 {
+            placing.push(Step::Zero { file:target_path.clone(), start:0, end:lead });
+        }
+        if target.size != extended {
+            placing.push(Step::Resize { file:target_path, from:extended, to:target.size });
+        }
+    }
+    copies.extend(staging);
+    copies.extend(placing);
+    Ok(copies)
+}
+
+// migrate by transforming the input directory itself, returns false when it was stopped and has to be resumed
+pub fn migrate(root:&Path, inputs:&[SourceFile], targets:&[TargetFile]) -> bool {
+    let steps = match plan_steps(root, inputs, targets) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Unable to migrate in place: {}", e);
+            return false;
+        }
+    };
+    fs::create_dir_all(journal_dir(root)).expect("Unable to create journal directory");
+    let mut journal = Journal::create(root, steps, false).expect("Unable to write journal");
+    finish(root, &mut journal, inputs, targets)
+}
+
+fn finish(root:&Path, journal:&mut Journal, inputs:&[SourceFile], targets:&[TargetFile]) -> bool {
+    if let Err(e) = execute(root, journal) {
+        println!("In-place migration stopped: {}", e);
+        println!("Run the in-place migration again to resume or roll it back, the journal is in '{}'", journal.path.display());
+        return false;
+    }
+    println!("In-place migration complete!");
+    // inputs that aren't part of the new torrent are left alone
+    let left:Vec<&SourceFile> = inputs.iter().filter(|i| !i.derived && i.mapping.is_none()).collect();
+    if !left.is_empty() {
+        println!("These files are not part of the new torrent and can be removed:");
+        for input in left {
+            if targets.iter().any(|t| root.join(&t.path) == input.path) {
+                println!("  {}", old_name(&input.display));
+            } else {
+                println!("  {}", input.display);
+            }
+        }
+    }
+    true
+}
+
+// offer to resume or roll back an interrupted in-place migration
+pub fn recover(root:&Path) {
+    let mut journal = match Journal::open(root) {
+        Ok(Some(j)) => j,
+        Ok(None) => {
+            // the journal wasn't finished, so nothing was changed yet
+            fs::remove_dir_all(journal_dir(root)).ok();
+            println!("Removed an unfinished in-place migration journal, nothing had been changed");
+            return;
+        },
+        Err(e) => {
+            println!("Unable to read the in-place migration journal '{}': {}", journal_path(root).display(), e);
+            return;
+        }
+    };
+    if journal.rollback {
+        println!("An interrupted roll back of an in-place migration was found in '{}' ({}/{} steps done)", root.display(), journal.done, journal.steps.len());
+        println!("Enter 'r' to resume the roll back or 'a' to abort [a]");
+    } else {
+        println!("An interrupted in-place migration was found in '{}' ({}/{} steps done)", root.display(), journal.done, journal.steps.len());
+        println!("Enter 'r' to resume it, 'b' to roll it back or 'a' to abort [a]");
+    }
+    let mut reply = String::new();
+    io::stdin().read_line(&mut reply).unwrap();
+    let result = match reply.trim() {
+        "r" => execute(root, &mut journal),
+        "b" if !journal.rollback => {
+            replay_chunk(root, &mut journal).and_then(|_| {
+                let steps = inverse_steps(root, &journal);
+                journal = Journal::create(root, steps, true)?;
+                execute(root, &mut journal)
+            })
+        },
+        _ => return,
+    };
+    match result {
+        Ok(_) => println!("Done, the journal has been removed"),
+        Err(e) => println!("Stopped again: {}, run again to continue", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Journal, Step, inverse_steps, journal_dir};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn scratch_dir(name:&str) -> PathBuf {
+        let root = env::temp_dir().join(format!("mtmigrate-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(journal_dir(&root)).unwrap();
+        root
+    }
+
+    fn steps() -> Vec<Step> {
+        vec![
+            Step::Backup { file:"a.flac".to_string(), start:0, end:10, backup:".mtmigrate/0".to_string() },
+            Step::Rename { from:"a.flac".to_string(), to:"Disc 1/b.flac".to_string() },
+            Step::Resize { file:"Disc 1/b.flac".to_string(), from:10, to:20 },
+            Step::Move { file:"Disc 1/b.flac".to_string(), from:0, to:10, length:10 },
+            Step::Create { file:"c.log".to_string(), size:5 },
+            Step::Zero { file:"Disc 1/b.flac".to_string(), start:0, end:10 },
+            Step::Restore { file:"a.flac".to_string(), start:0, backup:".mtmigrate/0".to_string() },
+            Step::Copy { from:"a.flac".to_string(), to:"a copy.flac".to_string() },
+            Step::Remove { file:".mtmigrate/0".to_string() },
+        ]
+    }
+
+    #[test]
+    fn steps_round_trip() {
+        for step in steps() {
+            assert_eq!(Step::parse(&step.to_line()), Some(step.clone()));
+        }
+        assert_eq!(Step::parse("move\ta.flac\t1\t2"), None);
+        assert_eq!(Step::parse("resize\ta.flac\tten\t20"), None);
+        assert_eq!(Step::parse("truncate\ta.flac\t10"), None);
+    }
+
+    #[test]
+    fn journal_progress_round_trip() {
+        let root = scratch_dir("journal");
+        {
+            let mut journal = Journal::create(&root, steps(), false).unwrap();
+            journal.record("done 0".to_string()).unwrap();
+            journal.record("done 1".to_string()).unwrap();
+            journal.record("done 2".to_string()).unwrap();
+            journal.record("moved 3 4".to_string()).unwrap();
+            journal.record("chunk 3 4 2".to_string()).unwrap();
+            // cut off by an interruption
+            journal.record("moved 3 ".to_string()).unwrap();
+        }
+        let journal = Journal::open(&root).unwrap().unwrap();
+        assert!(!journal.rollback);
+        assert_eq!(journal.steps, steps());
+        assert_eq!((journal.done, journal.moved, journal.pending), (3, 4, Some((4, 2))));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn inverse_of_interrupted_move() {
+        let root = scratch_dir("inverse");
+        let mut journal = Journal::create(&root, steps(), false).unwrap();
+        journal.done = 3;
+        journal.moved = 4;
+        // the move goes back to front, the last 4 bytes were moved
+        assert_eq!(inverse_steps(&root, &journal), vec![
+            Step::Move { file:"Disc 1/b.flac".to_string(), from:16, to:6, length:4 },
+            Step::Resize { file:"Disc 1/b.flac".to_string(), from:20, to:10 },
+            Step::Rename { from:"Disc 1/b.flac".to_string(), to:"a.flac".to_string() },
+            Step::Restore { file:"a.flac".to_string(), start:0, backup:".mtmigrate/0".to_string() },
+        ]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn inverse_of_interrupted_rename() {
+        let root = scratch_dir("rename");
+        let mut journal = Journal::create(&root, steps(), false).unwrap();
+        journal.done = 1;
+        // the rename is undone only when it happened before the interruption
+        assert_eq!(inverse_steps(&root, &journal), vec![
+            Step::Restore { file:"a.flac".to_string(), start:0, backup:".mtmigrate/0".to_string() },
+        ]);
+        fs::create_dir_all(root.join("Disc 1")).unwrap();
+        fs::write(root.join("Disc 1/b.flac"), b"data").unwrap();
+        assert_eq!(inverse_steps(&root, &journal), vec![
+            Step::Rename { from:"Disc 1/b.flac".to_string(), to:"a.flac".to_string() },
+            Step::Restore { file:"a.flac".to_string(), start:0, backup:".mtmigrate/0".to_string() },
+        ]);
+
+        // a backup that didn't complete has nothing to restore
+        journal.done = 0;
+        assert_eq!(inverse_steps(&root, &journal), vec![]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use migration::bip_metainfo::{Metainfo};
+use std::cmp::{min, max};
+use std::ops::Range;
+
+// the geometry of the files and pieces of a torrent, all in exact integer arithmetic
+#[derive(Debug, Clone)]
+pub struct TorrentLayout {
+    piece_length: u64,
+    total_size: u64,
+    piece_count: usize,
+    files: Vec<Range<u64>>, // byte range of each file within the torrent data
+}
+
+impl TorrentLayout {
+    pub fn new(torrent_meta:&Metainfo) -> TorrentLayout {
+        let info = torrent_meta.info();
+        let sizes:Vec<u64> = info.files().map(|f| f.length()).collect();
+        TorrentLayout::from_sizes(info.piece_length(), &sizes)
+    }
+
+    pub fn from_sizes(piece_length:u64, sizes:&[u64]) -> TorrentLayout {
+        let mut files = Vec::with_capacity(sizes.len());
+        let mut offset = 0;
+        for size in sizes {
+            files.push(offset..offset + size);
+            offset += size;
+        }
+        let piece_count = offset.div_ceil(piece_length) as usize;
+        TorrentLayout { piece_length, total_size:offset, piece_count, files }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.piece_count
+    }
+
+    // byte range of a file within the torrent data
+    pub fn file_range(&self, file:usize) -> Range<u64> {
+        self.files[file].clone()
+    }
+
+    pub fn file_size(&self, file:usize) -> u64 {
+        self.files[file].end - self.files[file].start
+    }
+
+    // zero length files are not part of any piece
+    pub fn is_empty(&self, file:usize) -> bool {
+        self.file_size(file) == 0
+    }
+
+    // byte range of a piece within the torrent data, the last piece can be shorter
+    pub fn piece_range(&self, piece:usize) -> Range<u64> {
+        let start = piece as u64 * self.piece_length;
+        start..min(start + self.piece_length, self.total_size)
+    }
+
+    pub fn piece_size(&self, piece:usize) -> u64 {
+        let range = self.piece_range(piece);
+        range.end - range.start
+    }
+
+    // the piece containing a byte of the torrent data
+    pub fn piece_at(&self, offset:u64) -> usize {
+        (offset / self.piece_length) as usize
+    }
+
+    // all pieces that contain data of a file
+    pub fn file_pieces(&self, file:usize) -> Range<usize> {
+        let range = &self.files[file];
+        if range.start == range.end {
+            return 0..0;
+        }
+        self.piece_at(range.start)..self.piece_at(range.end - 1) + 1
+    }
+
+    // pieces that contain data of this file only
+    pub fn interior_pieces(&self, file:usize) -> Range<usize> {
+        let range = &self.files[file];
+        if range.start == range.end {
+            return 0..0;
+        }
+        let first = range.start.div_ceil(self.piece_length) as usize;
+        // the last piece of the torrent ends at the end of the data instead of a piece boundary
+        let last = if range.end == self.total_size { self.piece_count } else { self.piece_at(range.end) };
+        if last > first { first..last } else { 0..0 }
+    }
+
+    // the non-empty files covering a piece, in torrent order
+    pub fn piece_files(&self, piece:usize) -> Vec<usize> {
+        let range = self.piece_range(piece);
+        // first file that ends after the start of the piece
+        let mut low = 0;
+        let mut high = self.files.len();
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.files[mid].end <= range.start { low = mid + 1; } else { high = mid; }
+        }
+        let mut result = Vec::new();
+        for file in low..self.files.len() {
+            if self.files[file].start >= range.end {
+                break;
+            }
+            if !self.is_empty(file) {
+                result.push(file);
+            }
+        }
+        result
+    }
+
+    pub fn is_interior(&self, piece:usize, file:usize) -> bool {
+        self.interior_pieces(file).contains(&piece)
+    }
+
+    // the range of a file (relative to the start of that file) that lies inside a piece
+    pub fn file_part(&self, piece:usize, file:usize) -> Range<u64> {
+        let piece_range = self.piece_range(piece);
+        let file_range = &self.files[file];
+        let start = max(piece_range.start, file_range.start);
+        let end = max(min(piece_range.end, file_range.end), start);
+        start - file_range.start..end - file_range.start
+    }
+
+    // the position of a piece relative to the start of a file, negative if it starts in a previous file
+    pub fn piece_position(&self, piece:usize, file:usize) -> i64 {
+        piece as i64 * self.piece_length as i64 - self.file_range(file).start as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TorrentLayout;
+
+    #[test]
+    fn files_ending_on_piece_boundaries() {
+        // a piece of its own, an empty file on the boundary, a file into the last piece and a file ending the torrent
+        let layout = TorrentLayout::from_sizes(10, &[10, 0, 15, 5]);
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.file_pieces(0), 0..1);
+        assert_eq!(layout.interior_pieces(0), 0..1);
+        assert_eq!(layout.file_pieces(2), 1..3);
+        assert_eq!(layout.interior_pieces(2), 1..2);
+        assert_eq!(layout.file_pieces(3), 2..3);
+        assert_eq!(layout.interior_pieces(3), 0..0);
+        assert_eq!(layout.piece_files(0), vec![0]);
+        assert_eq!(layout.piece_files(1), vec![2]);
+        assert_eq!(layout.piece_files(2), vec![2, 3]);
+    }
+
+    #[test]
+    fn zero_size_files() {
+        let layout = TorrentLayout::from_sizes(10, &[0, 12, 0, 0, 8, 0]);
+        for &file in &[0, 2, 3, 5] {
+            assert!(layout.is_empty(file));
+            assert_eq!(layout.file_pieces(file), 0..0);
+            assert_eq!(layout.interior_pieces(file), 0..0);
+        }
+        assert_eq!(layout.piece_files(0), vec![1]);
+        assert_eq!(layout.piece_files(1), vec![1, 4]);
+        assert_eq!(layout.file_part(1, 4), 0..8);
+        assert_eq!(layout.file_part(0, 4), 0..0);
+    }
+
+    #[test]
+    fn last_short_piece() {
+        let layout = TorrentLayout::from_sizes(10, &[25]);
+        assert_eq!(layout.piece_count(), 3);
+        assert_eq!(layout.piece_range(2), 20..25);
+        assert_eq!(layout.piece_size(2), 5);
+        // the short last piece holds only data of the file
+        assert_eq!(layout.interior_pieces(0), 0..3);
+
+        let layout = TorrentLayout::from_sizes(10, &[22, 3]);
+        assert_eq!(layout.interior_pieces(0), 0..2);
+        assert_eq!(layout.file_pieces(1), 2..3);
+        assert_eq!(layout.interior_pieces(1), 0..0);
+        assert_eq!(layout.piece_files(2), vec![0, 1]);
+        assert_eq!(layout.file_part(2, 1), 0..3);
+        assert_eq!(layout.piece_position(2, 1), -2);
+    }
+}
@@ -1,262 +1,321 @@
 use ::migration::{SourceFile, TargetFile};
-use std::io::prelude::*;
 use std::io;
-use std::io::{Read,SeekFrom};
-use std::fs::File;
-use std::collections::HashMap;
 use migration::bip_metainfo::{Metainfo};
-use migration::sha1::{Sha1, Digest};
-use migration::rayon::prelude::*;
-use migration::rayon::iter::{IntoParallelIterator, ParallelIterator};
+use migration::search;
+use migration::search::SearchWindow;
+use migration::reencode;
+use migration::repair;
+use migration::packing;
+use migration::variant;
+use migration::mp4;
+use migration::riff;
+use migration::ogg;
+use migration::frames;
+use migration::repair::Corruption;
+use migration::layout::TorrentLayout;
+use migration::table::PieceTable;
+use migration::filemapping;
 
-#[derive(Debug)]
-struct PieceResult {
-    files: Vec<usize>,
-    success: bool,
+// set an offset on a target and keep it when it makes the file verify, otherwise restore the previous one
+fn confirm_offset(index:usize, offset:i64, inputs:&[SourceFile], targets:&mut [TargetFile], table:&mut PieceTable) -> bool {
+    let previous = targets[index].offset;
+    targets[index].offset = offset;
+    table.update_file(index, inputs, targets);
+    if table.file_result(index, targets).ratio().is_some_and(|r| r >= 0.2) {
+        return true;
+    }
+    targets[index].offset = previous;
+    table.update_file(index, inputs, targets);
+    false
 }
 
-#[derive(Debug, Clone)]
-struct FileResult {
-    good:u32,
-    total:u32,
-}
+// search the pieces of a target in its mapped input, escalating the search until an offset is confirmed
+// shifts are the offsets that worked for other targets, as a whole release often shifts by the same amount
+fn piece_search(index:usize, torrent_meta:&Metainfo, inputs:&[SourceFile], targets:&mut [TargetFile], table:&mut PieceTable, shifts:&[(usize, i64)]) -> Option<i64> {
+    let mappedfile = targets[index].mapping.expect("Mapped file not found");
+    let input = &inputs[mappedfile];
+    let input_size = inputs[mappedfile].size;
+    let layout = TorrentLayout::new(torrent_meta);
+    let hashes:Vec<&[u8]> = torrent_meta.info().pieces().collect();
+    let interior = layout.interior_pieces(index);
+    if interior.start == interior.end {
+        println!("  {}: no interior pieces to search for", targets[index].path.to_string_lossy());
+        return None;
+    }
+    let first = interior.start;
+    let last = interior.end - 1;
+    let middle = first + (last - first) / 2;
+    let windowsize = (layout.file_size(index) as i64 - input_size as i64).abs() + layout.piece_size(middle) as i64;
+    let full = (layout.file_size(index) + input_size) as i64;
 
-// run a hash check on the given configuration, onlyscanfile allows for hash checking a single file
-fn hash_check(torrent_meta:&Metainfo, inputs:&Vec<SourceFile>, targets:&Vec<TargetFile>, onlyscanfile:Option<usize>) -> Vec<FileResult> {
-    let info = torrent_meta.info();
-    let piece_length = info.piece_length();
-    let pieces = info.pieces();
-    // put the pieces into a vec so we can use Rayon on them
-    let pieces_vec:Vec<&[u8]> = pieces.collect();
+    // in raw audio streams only the offsets that keep the frames aligned are searched, a few per frame
+    let aligned = match targets[index].extension.as_ref().and_then(|e| frames::Stream::from_extension(e)) {
+        Some(stream) => match frames::scan(input, stream) {
+            Ok(frames) if !frames.is_empty() => Some(frames::aligned_offsets(&frames, layout.file_size(index))),
+            Ok(_) => None,
+            Err(e) => {
+                println!("  Unable to scan the frames of {}: {}", input.display, e);
+                None
+            }
+        },
+        None => None,
+    };
 
-    // optionally filter a piece range if we ask for a single file
-    let mut scan_range:bool = false;
-    let mut piece_range = (0,0);
-    if let Some(f) = onlyscanfile {
-        scan_range = true;
-        // get the byte range of the target
-        let mut file_offset = 0;
-        let mut file_size = 0;
-        for target in targets.iter().enumerate() {
-            if target.0 == f {
-                file_size = target.1.size;
-                break;
+    let stages = vec![
+        ("middle piece", vec![(middle, windowsize)]),
+        ("first and last interior pieces", vec![(first, windowsize), (last, windowsize)]),
+        ("middle piece with a doubled window", vec![(middle, windowsize * 2)]),
+        ("middle piece in the whole file", vec![(middle, full)]),
+    ];
+    for (name, windows) in stages {
+        println!("  {}: searching {}{}", targets[index].path.to_string_lossy(), name, if aligned.is_some() { " at frame aligned positions" } else { "" });
+        for (piece, size) in windows {
+            let window = match SearchWindow::around(&layout, index, piece, size, input_size) {
+                Some(w) => w,
+                None => continue,
+            };
+            let found = match aligned {
+                Some(ref offsets) => {
+                    let positions:Vec<u64> = offsets.iter().map(|o| window.normal + o)
+                        .filter(|p| *p >= window.start as i64 && *p < window.end as i64).map(|p| p as u64).collect();
+                    search::search_positions(input, hashes[piece], &positions, window.length)
+                },
+                None => search::search_file(input, hashes[piece], &window),
+            };
+            if let Some(position) = found {
+                let offset = window.offset(position);
+                if confirm_offset(index, offset, inputs, targets, table) {
+                    println!("  Found offset {}", offset);
+                    return Some(offset);
+                }
+                println!("  Found offset {} but the file doesn't verify with it", offset);
             }
-            file_offset += target.1.size;
         }
-        // calculate the piece ranges for this
-        piece_range = (
-            (file_offset as f32 / piece_length as f32) as usize,
-            ((file_offset + file_size) as f32 / piece_length as f32) as usize
-        );
     }
 
-    // iterate the pieces and calculate the results in parallel
-    let results:Vec<PieceResult> = pieces_vec.par_iter().enumerate().filter(|item| {
-        !scan_range || (item.0 >= piece_range.0 && item.0 < piece_range.1)
-    }).map(|item| {
-        // calculate the offset of this piece
-        let piece_offset = item.0 as u64 * piece_length;
-
-        // define the starting point file
-        let mut file_offset = 0;
-        let mut curfile = &targets[0];
-        for target in targets {
-            curfile = target;
-            file_offset += target.size;
-            if file_offset > piece_offset {
-                file_offset -= target.size;
-                break;
-            }
+    // try the offsets of the other files, nearest neighbours first
+    let mut candidates:Vec<&(usize, i64)> = shifts.iter().filter(|s| s.0 != index).collect();
+    candidates.sort_by_key(|s| (s.0 as i64 - index as i64).abs());
+    let mut tried = Vec::new();
+    println!("  {}: trying the offsets of {} other files", targets[index].path.to_string_lossy(), candidates.len());
+    for &&(_, offset) in candidates.iter() {
+        if tried.contains(&offset) {
+            continue;
         }
-        file_offset = piece_offset - file_offset;
-
-        // keep track of result and files that are part of this piece
-        let mut piece_result = PieceResult { files:Vec::new(), success:false };
-
-        // create buffer for this piece
-        let mut buffer:Vec<u8> = Vec::with_capacity(piece_length as usize);
-        
-        // fill up the buffer
-        while buffer.len() < piece_length as usize {
-            piece_result.files.push(curfile.index);
-            if let Some(mapping) = curfile.mapping {
-                // load piece of the file into buffer
-                let mut file = File::open(&inputs[mapping].path).unwrap();
-                // check if we are not trying negative seek, in that case, mark the piece as failed immediately
-                if (file_offset as i64 + curfile.offset) < 0 {
-                    piece_result.success = false;
-                    return piece_result;
-                }
-                let total_offset  = (file_offset as i64 + curfile.offset) as u64;
-                file.seek(SeekFrom::Start(total_offset as u64)).expect("Unable to seek in file");
-                // put a cap on the number of bytes taken to not get any bytes of the (offset adjusted) end of the file
-                let mut numbytes = piece_length-buffer.len() as u64;
-                if total_offset + numbytes > (curfile.size as i64 + curfile.offset) as u64 {
-                    numbytes = (curfile.size as i64 - total_offset as i64 + curfile.offset) as u64;
-                }
-                file.take(numbytes).read_to_end(&mut buffer).expect("Unable to read file");
-                // (Minor TODO: if the input is smaller than the target and we haven't filled the buffer, 
-                // we might want to add padding to make sure we don't incorrectly mark a subsequent file as part of this piece)
-            } else {
-                // no mapping available, which means we can give up on this piece
-                // (Minor TODO: this can cause pieces not to show up as non matches for boundary pieces)
-                piece_result.success = false;
-                return piece_result;
-            }
-            // take next file if available
-            if let Some(newf) = targets.get(curfile.index + 1) {
-                file_offset = 0;
-                curfile = newf;
-            } else {
-                break;
-            }
+        tried.push(offset);
+        let window = match SearchWindow::at(&layout, index, middle, offset, input_size) {
+            Some(w) => w,
+            None => continue,
+        };
+        if search::search_file(input, hashes[middle], &window).is_some() && confirm_offset(index, offset, inputs, targets, table) {
+            println!("  Found offset {}", offset);
+            return Some(offset);
         }
-        // sha1 on the created buffer, check if it matches the piece
-        piece_result.success = Sha1::digest(&buffer).as_slice() == *item.1;
+    }
+    println!("  No offset found");
+    None
+}
 
-        piece_result
+// look for the pieces of a target in the other audio inputs, in case the mapping put the wrong input on it
+// unmapped inputs are searched first, an input mapped to another failing target is swapped with ours
+fn cross_search(index:usize, torrent_meta:&Metainfo, inputs:&mut [SourceFile], targets:&mut [TargetFile], table:&mut PieceTable) -> Option<usize> {
+    let layout = TorrentLayout::new(torrent_meta);
+    let hashes:Vec<&[u8]> = torrent_meta.info().pieces().collect();
+    let current = targets[index].mapping;
+    let mut candidates:Vec<usize> = (0..inputs.len()).filter(|i| {
+        inputs[*i].is_audio && !inputs[*i].derived && Some(*i) != current &&
+            inputs[*i].mapping.is_none_or(|m| table.file_result(m, targets).ratio().is_none_or(|r| r < 0.2))
     }).collect();
-
-    // merge these results into a hashmap
-    let merged_results:HashMap<usize, Vec<bool>> = results.iter().fold(HashMap::new(), |mut acc, x| {
-        for index in &x.files {
-            if !acc.contains_key(index) {
-                acc.insert(*index, Vec::new());
+    candidates.sort_by_key(|i| inputs[*i].mapping.is_some());
+    println!("  {}: searching {} other inputs", targets[index].path.to_string_lossy(), candidates.len());
+    for candidate in candidates {
+        let window = match search::middle_window(&layout, index, inputs[candidate].size) {
+            Some(w) => w,
+            None => continue,
+        };
+        if let Some(position) = search::search_file(&inputs[candidate], hashes[window.piece], &window) {
+            let offset = window.offset(position);
+            let other = inputs[candidate].mapping;
+            inputs[candidate].mapping = Some(index);
+            if let Some(c) = current {
+                inputs[c].mapping = other;
             }
-            acc.get_mut(&index).unwrap().push(x.success);
+            let changed = filemapping::assign_mapping(inputs, targets);
+            targets[index].offset = offset;
+            table.update_files(&changed, inputs, targets);
+            println!("  Found in {} at offset {}, mapping corrected{}", inputs[candidate].display, offset,
+                other.map_or(String::new(), |o| format!(" (swapped with {})", targets[o].path.to_string_lossy())));
+            return Some(candidate);
         }
-        acc
-    });
+    }
+    println!("  Not found in any other input");
+    None
+}
 
-    // and get the results for each target file into a vec
-    let result:Vec<FileResult> = targets.iter().map(|target| {
-        let mut good = 0;
-        let mut total = 1;
-        if let Some(info) = merged_results.get(&target.index) {
-            good = info.iter().fold(0,|mut acc,x| {
-                if *x { acc += 1; }
-                acc
+// try alternative container layouts of the input, without a search the layouts are only checked at the left and
+// right aligned offsets, a search also looks for the middle piece in every layout and takes a while
+fn container_search(index:usize, torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], table:&mut PieceTable, search:bool) -> bool {
+    let mapping = match targets[index].mapping {
+        Some(m) => m,
+        None => return false,
+    };
+    let found = match targets[index].extension.as_deref() {
+        Some("m4a") => {
+            let variants = mp4::layouts(&inputs[mapping]).into_iter();
+            variant::use_best_variant(index, torrent_meta, inputs, targets, table, variants, search)
+        },
+        Some("wav") | Some("aiff") => {
+            // the middle piece is audio, finding it tells where the audio chunk starts in the target
+            let target_start = if search {
+                riff::audio_start(&inputs[mapping]).and_then(|start| {
+                    middle_search(index, torrent_meta, &inputs[mapping]).map(|offset| start as i64 - offset)
+                })
+            } else {
+                None
+            };
+            let variants = riff::layouts(&inputs[mapping], targets[index].size, target_start).into_iter();
+            variant::use_best_variant(index, torrent_meta, inputs, targets, table, variants, search)
+        },
+        Some("ogg") | Some("opus") => {
+            // the numberings are generated one at a time as patches of the input
+            let source = inputs[mapping].clone();
+            let variants = (-ogg::MAX_PAGE_DELTA..ogg::MAX_PAGE_DELTA + 1).filter(|d| *d != 0).filter_map(|delta| {
+                ogg::renumber(&source, delta).map(|segments| (format!("pages renumbered by {:+}", delta), segments))
             });
-            total = info.len() as u32
-        } 
-        FileResult { good, total }
-    }).collect();
-
-    // if this is a single file hash, just return a vec with that only result
-    if let Some(f) = onlyscanfile {
-        return vec![result[f].clone()];
+            variant::use_best_variant(index, torrent_meta, inputs, targets, table, variants, search)
+        },
+        _ => return false,
     };
-
-    result
+    found && table.file_result(index, targets).ratio().is_some_and(|r| r >= 0.2)
 }
 
-fn piece_search(index:usize,torrent_meta:&Metainfo, inputs:&Vec<SourceFile>, targets:&Vec<TargetFile>) -> Option<i64> {
-    // get the offsets of our target file
-    let mut file_offset = 0;
-    let mut file_size = 0;
-    for target in targets.iter().enumerate(){
-        if target.0 == index {
-            file_size = target.1.size;
-            break;
-        }
-        file_offset += target.1.size;
-    }
-    
-    // calculate the middle peace for this file
-    let piece_length = torrent_meta.info().piece_length();
-    let middle_piece = ((file_offset as f64 + (file_size as f64 / 2.0)) / piece_length as f64) as usize;
-    
-    // get the hash for this piece
-    let piece = torrent_meta.info().pieces().nth(middle_piece).unwrap();
-    
-    // load the whole input file into a vec
-    let mappedfile = targets[index].mapping.expect("Mapped file not found");
-    let inputfile = &inputs[mappedfile];
-    let mut buffer:Vec<u8> = Vec::with_capacity(inputfile.size as usize);
-    let mut file = File::open(&inputfile.path).expect("Unable to open file for reading");
-    file.read_to_end(&mut buffer).expect("Unable to read file");
-
-    // determine our window size
-    let windowsize = (targets[index].size as i64 - inputfile.size as i64).abs() + piece_length as i64;
-    
-    // do the search
-    // (TODO: this iterator should start in the middle instead to potentially speed up the search considerably)
-    let result = (((file_size as f64 / 2.0) as i64 - windowsize) as usize..((file_size as f64 / 2.0) as i64 + windowsize) as usize).into_par_iter().find_any(|x| {
-        if Sha1::digest(&buffer[*x..*x+piece_length as usize]).as_slice() == piece {
-            return true;
-        }
-        false
-    });
-    if let Some(offs) = result {
-        // figure out where this piece offset should normally be
-        let normal = piece_length as u64 * middle_piece as u64 - file_offset;
-        let offset = normal as i64 - offs as i64;
-        return Some(offset);
+// check the middle piece of a target at one offset in an input, None when the target has no interior piece
+pub fn middle_check(index:usize, torrent_meta:&Metainfo, input:&SourceFile, offset:i64) -> Option<bool> {
+    let layout = TorrentLayout::new(torrent_meta);
+    let interior = layout.interior_pieces(index);
+    if interior.start == interior.end {
+        return None;
     }
+    let piece = interior.start + (interior.end - interior.start) / 2;
+    let hash = torrent_meta.info().pieces().nth(piece).unwrap();
+    Some(SearchWindow::at(&layout, index, piece, offset, input.size).is_some_and(|w| search::search_file(input, hash, &w).is_some()))
+}
 
-    None
+// search the middle piece of a target in an input, this reads only the window around it
+pub fn middle_search(index:usize, torrent_meta:&Metainfo, input:&SourceFile) -> Option<i64> {
+    let layout = TorrentLayout::new(torrent_meta);
+    let window = search::middle_window(&layout, index, input.size)?;
+    let hash = torrent_meta.info().pieces().nth(window.piece).unwrap();
+    search::search_file(input, hash, &window).map(|position| window.offset(position))
 }
 
-fn print_hash_result(result:&Vec<FileResult>, targets:&Vec<TargetFile>) {
+fn print_hash_result(table:&PieceTable, inputs:&[SourceFile], targets:&[TargetFile]) {
     let max = targets.iter().map(|e| e.path.to_string_lossy().len()).max().unwrap();
-    let mut total_good = 0;
-    let mut total_total = 0;
     for (index, target) in targets.iter().enumerate() {
-        let info = &result[index];
-        total_good += info.good;
-        total_total += info.total;
-        println!("  {:3$} = {:.1}%{}", 
+        let info = table.file_result(index, targets);
+        let ratio = match info.ratio() {
+            Some(r) => format!("{:.1}%", r * 100.0),
+            None => "n/a".to_string(),
+        };
+        // only detail the boundary pieces when they are relevant
+        let mut details = Vec::new();
+        if info.boundary_total > 0 || info.blocked > 0 {
+            details.push(format!("interior {}/{}", info.good, info.total));
+            if info.boundary_total > 0 {
+                details.push(format!("boundary {}/{}", info.boundary_good, info.boundary_total));
+            }
+            if info.blocked > 0 {
+                details.push(format!("{} blocked by neighbour", info.blocked));
+            }
+        }
+        // a failure on a bit-perfect input is a real content difference between the torrents
+        let source = match target.mapping.and_then(|m| inputs[m].verified) {
+            Some(true) if info.verified() < info.total + info.boundary_total => " (input is bit-perfect against the source torrent)",
+            Some(false) => " (input is corrupted)",
+            _ => "",
+        };
+        println!("  {:5$} = {}{}{}{}", 
             target.path.to_string_lossy(),
-            (info.good as f32/info.total as f32)*100.0,
+            ratio,
+            if details.is_empty() { String::new() } else { format!(" ({})", details.join(", ")) },
             if target.mapping == None { " (unmapped)" } else { "" },
+            source,
             max+1,
         );
     }
-    println!("Overall hash result: {:.2}%", (total_good as f32 / total_total as f32) * 100.0);
+    let (good, checked, unchecked) = table.totals();
+    println!("Overall hash result: {:.2}%{}", (good as f32 / (checked + unchecked) as f32) * 100.0,
+        if unchecked > 0 { format!(" ({} pieces can't be checked because of unmapped files)", unchecked) } else { String::new() });
 }
 
-pub fn run_matcher(torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:&mut Vec<TargetFile>) {
+pub fn run_matcher(torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], table:&mut PieceTable) {
     // do a first hash check
-    let result = hash_check(&torrent_meta, &inputs, &targets, None);
+    table.check_all(inputs, targets);
     println!("Initial hash test result:");
-    print_hash_result(&result,&targets);
+    print_hash_result(table, inputs, targets);
 
     // take actions on failed files music files
     let mut corrected_pieces = 0;
+    let mut shifts = Vec::new();
     let mut agreed_to_search = false;
     let mut rejected_search = false;
+    let mut agreed_to_reencode = false;
+    let mut rejected_reencode = false;
     for index in 0..targets.len() { // not iterating over targets directly to avoid reference
-        let info = &result[index];
-        if (info.good as f32/info.total as f32) < 0.2 && targets[index].is_audio {
+        // use the current result, an earlier correction may have swapped a mapping onto this file
+        let info = table.file_result(index, targets);
+        if info.ratio().is_some_and(|r| r < 0.2) && targets[index].is_audio {
             // only if we have a mapping
             if let Some(mapping) = targets[index].mapping {
                 // keep track of how many we are correcting
                 corrected_pieces += 1;
                 // adjust the offset to right aligned instead of left aligned
                 targets[index].offset = inputs[mapping].size as i64 - targets[index].size as i64;
-                // hash check this single file
-                let fileresult = &hash_check(&torrent_meta, &inputs, &targets, Some(index))[0];
+                // hash check the pieces of this single file
+                table.update_file(index, inputs, targets);
+                let fileresult = table.file_result(index, targets);
                 // if still not good, we'll try a more in depth piece search
-                if (fileresult.good as f32/fileresult.total as f32) < 0.2 {
-                    if !rejected_search {
-                        if !agreed_to_search {
-                            println!("Realign not succesful on at least one file, want to try a (slow and CPU heavy) piece search? (y/n) [n]");
-                            let mut reply = String::new();
-                            io::stdin().read_line(&mut reply).unwrap();
-                            match reply.trim() {
-                                "y" | "yes" => {
-                                    agreed_to_search = true;
-                                },
-                                _ => {
-                                    rejected_search = true;
-                                }
+                if fileresult.ratio().is_some_and(|r| r >= 0.2) {
+                    shifts.push((index, targets[index].offset));
+                } else if !container_search(index, torrent_meta, inputs, targets, table, false) && !rejected_search {
+                    if !agreed_to_search {
+                        println!("Realign not succesful on at least one file, want to try a (slow and CPU heavy) piece search? (y/n) [n]");
+                        let mut reply = String::new();
+                        io::stdin().read_line(&mut reply).unwrap();
+                        match reply.trim() {
+                            "y" | "yes" => {
+                                agreed_to_search = true;
+                            },
+                            _ => {
+                                rejected_search = true;
                             }
                         }
-                        if agreed_to_search {
-                            // do a piece search on this file as final attempt
-                            if let Some(offset) = piece_search(index,&torrent_meta, &inputs, &targets) {
-                                targets[index].offset = -offset;
+                    }
+                    if agreed_to_search {
+                        // search the container layouts and do a piece search on this file as final attempt
+                        if container_search(index, torrent_meta, inputs, targets, table, true) {
+                            shifts.push((index, targets[index].offset));
+                        } else if let Some(offset) = piece_search(index, torrent_meta, inputs, targets, table, &shifts) {
+                            shifts.push((index, offset));
+                        } else if cross_search(index, torrent_meta, inputs, targets, table).is_some() {
+                            shifts.push((index, targets[index].offset));
+                        } else if targets[index].extension.as_ref().is_some_and(|e| e == "flac") && !rejected_reencode {
+                            // the audio doesn't appear anywhere in the input, it might be encoded with different settings
+                            if !agreed_to_reencode {
+                                println!("Piece search not succesful on at least one FLAC file, want to try re-encoding with different encoder settings (slow, only reproduces files encoded with flac -0 to -2)? (y/n) [n]");
+                                let mut reply = String::new();
+                                io::stdin().read_line(&mut reply).unwrap();
+                                match reply.trim() {
+                                    "y" | "yes" => {
+                                        agreed_to_reencode = true;
+                                    },
+                                    _ => {
+                                        rejected_reencode = true;
+                                    }
+                                }
+                            }
+                            if agreed_to_reencode {
+                                reencode::reencode_search(index, torrent_meta, inputs, targets, table);
                             }
                         }
                     }
@@ -265,11 +324,62 @@ pub fn run_matcher(torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:
         }
     }
 
-    // check if we made any corrections
+    // pieces packing small files only verify with the right input for each of them
+    let pieces = packing::small_file_candidates(torrent_meta, targets, table);
+    if !pieces.is_empty() {
+        println!("{} failing pieces hold small files that may be mapped to the wrong input, want to try the combinations of their plausible inputs (slow)? (y/n) [n]", pieces.len());
+        let mut reply = String::new();
+        io::stdin().read_line(&mut reply).unwrap();
+        if let "y" | "yes" = reply.trim() {
+            corrected_pieces += packing::solve_small_files(torrent_meta, inputs, targets, table, &pieces);
+        }
+    }
+
+    // failing pieces inside otherwise good files are probably bit rot, offer to repair them
+    let candidates = repair::repair_candidates(torrent_meta, inputs, targets, table);
+    if !candidates.is_empty() {
+        println!("{} failing pieces lie inside otherwise good files, want to try repairing them (slow and CPU heavy)? (y/n) [n]", candidates.len());
+        let mut reply = String::new();
+        io::stdin().read_line(&mut reply).unwrap();
+        if let "y" | "yes" = reply.trim() {
+            corrected_pieces += repair::repair_pieces(torrent_meta, inputs, targets, table, &candidates, Corruption::BitFlip);
+            let candidates = repair::repair_candidates(torrent_meta, inputs, targets, table);
+            if !candidates.is_empty() {
+                println!("{} pieces can't be repaired with a single bit flip, want to try single byte substitutions (much slower)? (y/n) [n]", candidates.len());
+                let mut reply = String::new();
+                io::stdin().read_line(&mut reply).unwrap();
+                if let "y" | "yes" = reply.trim() {
+                    corrected_pieces += repair::repair_pieces(torrent_meta, inputs, targets, table, &candidates, Corruption::ByteSubstitution);
+                }
+            }
+        }
+    }
+
+    // check if we made any corrections, the table is already up to date with them
     if corrected_pieces > 0 {
-        // do a final hash check on the data
-        let result = hash_check(&torrent_meta, &inputs, &targets, None);
         println!("Hash test result after optimization:");
-        print_hash_result(&result,&targets);        
+        print_hash_result(table, inputs, targets);
+    }
+
+    // allow manual corrections of the mapping, only the changed files and the pieces they share with their neighbours are checked again
+    loop {
+        println!("Enter 'c' to continue or 'm' to manually adjust the mapping [c]");
+        let mut reply = String::new();
+        io::stdin().read_line(&mut reply).unwrap();
+        match reply.trim() {
+            "" | "c" => {
+                break;
+            },
+            "m" => {
+                filemapping::map_manual(inputs, targets);
+                let changed = filemapping::assign_mapping(inputs, targets);
+                table.update_files(&changed, inputs, targets);
+                println!("Hash test result:");
+                print_hash_result(table, inputs, targets);
+            },
+            _ => {
+                println!("Unrecognized option.");
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::bip_metainfo::{Metainfo};
+use migration::cache::PieceCache;
+use migration::layout::TorrentLayout;
+use migration::table::PieceTable;
+use migration::verify;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+// what happened to the pieces of a file
+#[derive(Debug, Clone, Default)]
+struct MergeResult {
+    kept: u32, // verified on disk already
+    written: u32, // written from the input
+    missing: u32, // neither the download nor the input has it
+}
+
+impl MergeResult {
+    fn count(&mut self, state:PieceState) {
+        match state {
+            PieceState::Kept => self.kept += 1,
+            PieceState::Written => self.written += 1,
+            PieceState::Missing => self.missing += 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PieceState {
+    Kept,
+    Written,
+    Missing,
+}
+
+// the files of the download in the output directory, a missing file is checked as unmapped
+fn download_files(torrent_meta:&Metainfo, targets:&[TargetFile], output:&Path) -> (Vec<PathBuf>, Vec<SourceFile>, Vec<TargetFile>) {
+    let mut root = output.to_path_buf();
+    if let Some(p) = torrent_meta.info().directory() {
+        root.push(p);
+    }
+    let paths:Vec<PathBuf> = targets.iter().map(|t| root.join(&t.path)).collect();
+    let mut inputs = Vec::new();
+    let mut disk_targets = targets.to_vec();
+    for (target, path) in disk_targets.iter_mut().zip(paths.iter()) {
+        target.offset = 0;
+        target.mapping = None;
+        let size = match path.metadata() {
+            Ok(ref m) if m.is_file() => m.len(),
+            _ => continue,
+        };
+        target.mapping = Some(inputs.len());
+        inputs.push(SourceFile {
+            path: path.clone(),
+            display: target.path.to_string_lossy().into_owned(),
+            extension: target.extension.clone(),
+            is_audio: target.is_audio,
+            size,
+            mapping: Some(target.index),
+            derived: false,
+            segments: None,
+            original: None,
+            verified: None,
+        });
+    }
+    (paths, inputs, disk_targets)
+}
+
+fn open_download(path:&Path, target:&TargetFile) -> File {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(path).expect("Unable to open download file");
+    // the client may not have allocated the file yet
+    if file.metadata().unwrap().len() < target.size {
+        file.set_len(target.size).unwrap();
+    }
+    file
+}
+
+// merge the input into a download that is in progress: a piece that verifies on disk is left alone, a piece that
+// doesn't is written from the input only when the input verifies for it, so good data is never overwritten
+// only pieces that were already failing are written to, an interruption can't damage the download
+pub fn merge(torrent_meta:&Metainfo, piece_cache:&PieceCache, inputs:&[SourceFile], targets:&[TargetFile], table:&PieceTable, output:&Path) {
+    let layout = TorrentLayout::new(torrent_meta);
+    let (paths, disk_inputs, disk_targets) = download_files(torrent_meta, targets, output);
+    println!("Checking the download in '{}'", output.display());
+    let mut disk_table = PieceTable::new(torrent_meta, piece_cache);
+    disk_table.check_all(&disk_inputs, &disk_targets);
+
+    let verified = |t:&PieceTable, piece:usize| t.piece_result(piece).is_some_and(|r| r.checked && r.success);
+    let mut results = vec![MergeResult::default(); targets.len()];
+    let mut total = MergeResult::default();
+    let mut files:HashMap<usize, File> = HashMap::new();
+    for piece in 0..layout.piece_count() {
+        let piece_files = layout.piece_files(piece);
+        let state = if verified(&disk_table, piece) {
+            PieceState::Kept
+        } else if verified(table, piece) {
+            for file in piece_files.iter() {
+                let target = &targets[*file];
+                let part = layout.file_part(piece, *file);
+                let data = verify::read_range(inputs, target.mapping.unwrap(), target.offset, part.start, part.end);
+                let handle = files.entry(*file).or_insert_with(|| open_download(&paths[*file], target));
+                handle.seek(SeekFrom::Start(part.start)).unwrap();
+                handle.write_all(&data).expect("Unable to write to download file");
+            }
+            PieceState::Written
+        } else {
+            PieceState::Missing
+        };
+        for file in piece_files {
+            results[file].count(state);
+        }
+        total.count(state);
+    }
+    for file in files.values() {
+        file.sync_data().ok();
+    }
+
+    println!("Merge complete:");
+    let max = targets.iter().map(|e| e.path.to_string_lossy().len()).max().unwrap_or(0);
+    for target in targets {
+        let result = &results[target.index];
+        println!("  {:width$} {} pieces kept, {} written from the input, {} missing", target.path.to_string_lossy(),
+            result.kept, result.written, result.missing, width = max);
+    }
+    println!("Overall: {} pieces kept, {} written from the input, {} missing", total.kept, total.written, total.missing);
+    println!("Recheck the torrent in your client so it picks up the written pieces");
+}
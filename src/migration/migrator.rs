@@ -1,16 +1,317 @@
-use ::migration::{SourceFile, TargetFile};
+use ::migration::{SourceFile, TargetFile, Strategy};
+use migration::overlay;
+use migration::overlay::InputFile;
+use migration::reflink;
+use migration::space;
+use migration::staging;
+use migration::staging::{Staging, Interrupted};
+use std::cmp::{min, max};
 use std::fs::File;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use migration::bip_metainfo::{Metainfo};
 use std::fs;
+use std::io;
 use std::io::prelude::*;
 use std::io::{Read,SeekFrom};
+use std::ops::Range;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
 
-pub fn migrate(torrent_meta:&Metainfo, inputs: &mut Vec<SourceFile>, targets: &mut Vec<TargetFile>, output: &str) {
+// bytes copied between progress updates, this is also the most that is copied in one go
+const CHUNK_SIZE:u64 = 8 << 20;
+
+// progress over all files of the migration
+struct Progress {
+    total: u64,
+    written: u64,
+    shared: u64,
+    started: Instant,
+}
+
+fn seconds(duration:Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+// transfer rate in bytes per second
+fn rate(done:u64, started:Instant) -> f64 {
+    let elapsed = seconds(started.elapsed());
+    if elapsed > 0.0 { done as f64 / elapsed } else { 0.0 }
+}
+
+// transfer rate and estimated time left, as shown in the progress line
+fn rate_and_eta(done:u64, total:u64, started:Instant) -> String {
+    let rate = rate(done, started);
+    if rate <= 0.0 {
+        return "-- MB/s".to_string();
+    }
+    let eta = ((total - done) as f64 / rate) as u64;
+    format!("{:.1} MB/s, ETA {}:{:02}", rate / 1e6, eta / 60, eta % 60)
+}
+
+// where the input data goes for a target: (position in the input, position in the output, length)
+// a positive offset skips into the input, a negative offset leaves padding at the start of the output
+pub fn copy_range(input_size:u64, target:&TargetFile) -> (u64, u64, u64) {
+    let (source_start, lead) = if target.offset > 0 { (target.offset as u64, 0) } else { (0, (-target.offset) as u64) };
+    let length = min(input_size.saturating_sub(source_start), target.size.saturating_sub(lead));
+    (source_start, lead, length)
+}
+
+// stream a range from the input into the output in chunks of a fixed size, the input is read through its
+// overlay so the data passes through a userspace buffer
+fn copy(source:&mut InputFile, destination:&mut File, length:u64, name:&str, progress:&mut Progress) {
+    let started = Instant::now();
+    let mut copied = 0;
+    while copied < length {
+        staging::check_interrupted();
+        let chunk = min(CHUNK_SIZE, length - copied);
+        let written = io::copy(&mut Read::by_ref(source).take(chunk), destination).expect("Unable to write to output file");
+        if written == 0 {
+            // the input got shorter since it was scanned, the rest is padding
+            break;
+        }
+        copied += written;
+        progress.written += written;
+        print!("\r  {}: {:.1}/{:.1} MB, {} (overall {:.0}%, {})\x1b[K", name, copied as f64 / 1e6, length as f64 / 1e6,
+            rate_and_eta(copied, length, started), progress.written as f64 * 100.0 / progress.total as f64,
+            rate_and_eta(progress.written, progress.total, progress.started));
+        io::stdout().flush().ok();
+    }
+    if length > 0 {
+        println!();
+    }
+}
+
+// what happens to a target file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Copy,
+    Hardlink,
+    Reflink(u64), // with the block size extents are shared in
+    Empty, // unmapped, only the size is reserved
+    Keep, // complete in the output of an earlier run
+    Verified, // only the ranges of verified pieces are written, the rest is left as a hole
+}
+
+// the actions for the target files, with the bytes that are expected to be written and shared
+pub struct Plan {
+    actions: Vec<Action>,
+    ranges: Vec<Vec<Range<u64>>>, // the verified ranges of the target files, only when writing verified data only
+    files: Vec<(u64, u64)>, // the bytes written and shared for every target file
+    written: u64,
+    shared: u64,
+}
+
+impl Plan {
+    // how a target file is written, for showing it to the user
+    pub fn describe(&self, file:usize) -> &'static str {
+        match self.actions[file] {
+            Action::Copy => "copied",
+            Action::Hardlink => "hardlinked",
+            Action::Reflink(_) => "reflinked",
+            Action::Empty => "not mapped, left empty",
+            Action::Keep => "kept",
+            Action::Verified => "verified pieces written",
+        }
+    }
+}
+
+// a file can be shared with its input when the input is the target byte for byte
+fn is_identical(input:&SourceFile, target:&TargetFile, complete:bool) -> bool {
+    complete && !input.derived && target.offset == 0 && input.size == target.size
+}
+
+// the part of a copy that can be cloned: (position in the input, position in the output, length)
+// extents can only be shared when both positions are aligned to the block size, which holds for every
+// block once the offset is a multiple of it, the unaligned head and tail are written
+fn clone_part(input:&SourceFile, target:&TargetFile, block_size:u64) -> Option<(u64, u64, u64)> {
+    // derived data is only partly in the file that is read
+    if input.segments.is_some() {
+        return None;
+    }
+    let (source_start, lead, length) = copy_range(input.size, target);
+    if input.size == target.size && target.offset == 0 {
+        return Some((0, 0, length));
+    }
+    if target.offset % block_size as i64 != 0 {
+        return None;
+    }
+    let start = lead.div_ceil(block_size) * block_size;
+    let end = (lead + length) / block_size * block_size;
+    if end <= start {
+        return None;
+    }
+    Some((source_start + start - lead, start, end - start))
+}
+
+// decide how every target file is written, reflinks are only used when the filesystem supports them
+// files that an earlier run already wrote completely are kept, with verified ranges only those parts of
+// incomplete files are written
+pub fn plan(inputs:&[SourceFile], targets:&[TargetFile], output:&str, complete:&[bool], kept:&[bool], verified:Option<Vec<Vec<Range<u64>>>>, strategy:Strategy) -> Plan {
+    let mut block_size = None;
+    if strategy == Strategy::Reflink {
+        if let Some(input) = targets.iter().filter_map(|t| t.mapping).next() {
+            block_size = reflink::detect(&inputs[input].path, Path::new(output));
+        }
+        if block_size.is_none() {
+            println!("Reflinks are not supported between the input and the output, files will be copied");
+        }
+    }
+    // a hardlink into another filesystem fails and is written as a copy, plan it as one so the space check counts it
+    let mut cross_device = false;
+    let mut plan = Plan { actions:Vec::new(), ranges:verified.unwrap_or_default(), files:Vec::new(), written:0, shared:0 };
+    for target in targets {
+        // the action with the bytes it writes and shares
+        let (action, written, shared) = match target.mapping {
+            _ if kept[target.index] => (Action::Keep, 0, 0),
+            None => (Action::Empty, 0, 0),
+            Some(m) if !plan.ranges.is_empty() && !complete[target.index] => {
+                (Action::Verified, verified_length(inputs[m].size, target, &plan.ranges[target.index]), 0)
+            },
+            Some(m) => {
+                let input = &inputs[m];
+                let length = copy_range(input.size, target).2;
+                match (strategy, block_size) {
+                    (Strategy::Hardlink, _) if is_identical(input, target, complete[target.index]) => {
+                        if reflink::same_device(&input.path, Path::new(output)) {
+                            (Action::Hardlink, 0, target.size)
+                        } else {
+                            cross_device = true;
+                            (Action::Copy, length, 0)
+                        }
+                    },
+                    (Strategy::Reflink, Some(block)) => match clone_part(input, target, block) {
+                        Some((_, _, shared)) => (Action::Reflink(block), length - shared, shared),
+                        None => (Action::Copy, length, 0),
+                    },
+                    _ => (Action::Copy, length, 0),
+                }
+            }
+        };
+        plan.actions.push(action);
+        plan.files.push((written, shared));
+        plan.written += written;
+        plan.shared += shared;
+    }
+    if cross_device {
+        println!("Hardlinks can't be made from an input on another filesystem than the output, those files will be copied");
+    }
+    plan
+}
+
+// the bytes the output will take on disk, written data is rounded up to whole blocks
+// preallocated files take their full size except for the extents they share
+fn allocated_size(plan:&Plan, targets:&[TargetFile], preallocate:bool, block:u64) -> u64 {
+    let round = |size:u64| size.div_ceil(block) * block;
+    targets.iter().map(|target| {
+        let (written, shared) = plan.files[target.index];
+        match plan.actions[target.index] {
+            Action::Keep | Action::Hardlink => 0,
+            _ if preallocate => round(target.size - shared),
+            _ => round(written),
+        }
+    }).sum()
+}
+
+// check that the output fits on its filesystem before anything is written, returns false when it doesn't
+pub fn check_space(plan:&Plan, targets:&[TargetFile], output:&str, preallocate:bool) -> bool {
+    let (available, block) = match space::available(Path::new(output)) {
+        Some(a) => a,
+        None => {
+            println!("Unable to determine the free space in '{}'", output);
+            return true;
+        }
+    };
+    let needed = allocated_size(plan, targets, preallocate, max(block, 1));
+    println!("Disk space: {:.1} MB needed, {:.1} MB available", needed as f64 / 1e6, available as f64 / 1e6);
+    if needed > available {
+        println!("Not enough free space in '{}', free up {:.1} MB first", output, (needed - available) as f64 / 1e6);
+        return false;
+    }
+    true
+}
+
+// the part of a verified range of a target that comes from the input: (position in the input, position in the output, length)
+// the rest of the range is padding, which is already zero in the hole
+fn verified_part(input_size:u64, target:&TargetFile, range:&Range<u64>) -> Option<(u64, u64, u64)> {
+    let start = max(range.start as i64 + target.offset, 0);
+    let end = min(range.end as i64 + target.offset, input_size as i64);
+    if end <= start {
+        return None;
+    }
+    Some((start as u64, (start - target.offset) as u64, (end - start) as u64))
+}
+
+fn verified_length(input_size:u64, target:&TargetFile, ranges:&[Range<u64>]) -> u64 {
+    ranges.iter().filter_map(|r| verified_part(input_size, target, r)).map(|p| p.2).sum()
+}
+
+pub fn print_plan(plan:&Plan, targets:&Vec<TargetFile>) {
+    if !plan.ranges.is_empty() {
+        println!("Verified coverage:");
+        let max = targets.iter().map(|e| e.path.to_string_lossy().len()).max().unwrap_or(0);
+        for target in targets {
+            let covered:u64 = plan.ranges[target.index].iter().map(|r| r.end - r.start).sum();
+            let percentage = if target.size > 0 { covered as f64 * 100.0 / target.size as f64 } else { 100.0 };
+            println!("  {:width$} {:5.1}% ({:.1} of {:.1} MB)", target.path.to_string_lossy(), percentage,
+                covered as f64 / 1e6, target.size as f64 / 1e6, width = max);
+        }
+    }
+    let count = |kind:fn(&Action) -> bool| plan.actions.iter().filter(|a| kind(a)).count();
+    let linked = count(|a| *a == Action::Hardlink);
+    let cloned = count(|a| matches!(*a, Action::Reflink(_)));
+    let kept = count(|a| *a == Action::Keep);
+    println!("Migration plan: {:.1} MB to write, {:.1} MB shared with the input ({} files hardlinked, {} files reflinked, {} files kept from an earlier run)",
+        plan.written as f64 / 1e6, plan.shared as f64 / 1e6, linked, cloned, kept);
+}
+
+// copy a range of the input to a position in the output
+fn copy_at(source:&mut InputFile, source_start:u64, destination:&mut File, destination_start:u64, length:u64, name:&str, progress:&mut Progress) {
+    source.seek(SeekFrom::Start(source_start)).expect("Unable to seek in input file");
+    destination.seek(SeekFrom::Start(destination_start)).expect("Unable to seek in output file");
+    copy(source, destination, length, name, progress);
+}
+
+// clone the aligned part of the input and write the head and tail around it, false when the filesystem refused
+fn write_reflink(source:&mut InputFile, input:&SourceFile, destination:&mut File, target:&TargetFile, block_size:u64, name:&str, progress:&mut Progress) -> bool {
+    let (source_start, lead, length) = copy_range(input.size, target);
+    let (clone_source, clone_start, clone_length) = match clone_part(input, target, block_size) {
+        Some(c) => c,
+        None => return false,
+    };
+    // the output gets its final size first, cloning replaces the blocks in the middle
+    destination.set_len(target.size).unwrap();
+    let cloned = if clone_length == target.size && input.size == target.size {
+        reflink::clone_file(source.file(), destination)
+    } else {
+        reflink::clone_range(source.file(), clone_source, destination, clone_start, clone_length)
+    };
+    if let Err(e) = cloned {
+        println!("  {}: unable to reflink ({}), copying instead", name, e);
+        return false;
+    }
+    progress.shared += clone_length;
+    if clone_start > lead {
+        copy_at(source, source_start, destination, lead, clone_start - lead, name, progress);
+    }
+    if clone_start + clone_length < lead + length {
+        let tail = clone_start + clone_length;
+        copy_at(source, source_start + tail - lead, destination, tail, lead + length - tail, name, progress);
+    }
+    if clone_length == length {
+        println!("  {}: reflinked", name);
+    }
+    true
+}
+
+// write all target files below the staging directory, every file gets a temporary name until it is complete
+fn write_files(torrent_meta:&Metainfo, inputs:&[SourceFile], targets:&[TargetFile], root:&Path, plan:&Plan, preallocate:bool) -> Progress {
+    let mut progress = Progress { total:plan.written, written:0, shared:0, started:Instant::now() };
     // create all the target files
     for target in targets {
+        staging::check_interrupted();
         // build the path to the file
-        let mut path = PathBuf::from(output);
+        let mut path = root.to_path_buf();
         if let Some(p) = torrent_meta.info().directory() {
             path.push(p);
         }
@@ -20,33 +321,104 @@ pub fn migrate(torrent_meta:&Metainfo, inputs: &mut Vec<SourceFile>, targets: &m
         if !parent.exists() {
             fs::create_dir_all(parent).unwrap();
         }
+        let name = target.path.to_string_lossy().into_owned();
+        let action = plan.actions[target.index];
+        if action == Action::Keep {
+            continue;
+        }
+        // identical files are hardlinked, this fails across filesystems in which case the file is copied
+        if action == Action::Hardlink {
+            let input = &inputs[target.mapping.unwrap()];
+            match fs::hard_link(&input.path, &path) {
+                Ok(_) => {
+                    println!("  {}: hardlinked", name);
+                    progress.shared += target.size;
+                    continue;
+                },
+                Err(e) => {
+                    println!("  {}: unable to hardlink ({}), copying instead", name, e);
+                    progress.total += target.size;
+                }
+            }
+        }
         // write the file based on input data
-        let mut file = File::create(&path).expect("Unable to write file");
+        let mut partial = path.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let mut file = File::create(&partial).expect("Unable to write file");
         match target.mapping {
+            Some(m) if action == Action::Verified => {
+                // the file gets its size first so everything that isn't written stays a hole
+                file.set_len(target.size).unwrap();
+                let mut sourcefile = overlay::open(&inputs[m]).expect("Unable to read input file");
+                for range in plan.ranges[target.index].iter() {
+                    if let Some((source_start, destination_start, length)) = verified_part(inputs[m].size, target, range) {
+                        copy_at(&mut sourcefile, source_start, &mut file, destination_start, length, &name, &mut progress);
+                    }
+                }
+            },
             Some(m) => {
                 // read the sourcefile
-                let mut sourcefile = File::open(&inputs[m].path).expect("Unable to read input file");
-                // if we have a positive offset, seek into the file and write it out
-                if target.offset > 0 {
-                    sourcefile.seek(SeekFrom::Start(target.offset as u64)).expect("Unable to seek in input file");
+                let mut sourcefile = overlay::open(&inputs[m]).expect("Unable to read input file");
+                let mut written = false;
+                if let Action::Reflink(block_size) = action {
+                    let shared = clone_part(&inputs[m], target, block_size).map_or(0, |c| c.2);
+                    written = write_reflink(&mut sourcefile, &inputs[m], &mut file, target, block_size, &name, &mut progress);
+                    if !written {
+                        progress.total += shared;
+                    }
                 }
-                // if we have a negative offset, add initial padding to the size of the offset
-                if target.offset < 0 {
-                    file.set_len((-target.offset) as u64).unwrap();
-                    file.seek(SeekFrom::Start((-target.offset) as u64)).unwrap();
+                if !written {
+                    let (source_start, lead, length) = copy_range(inputs[m].size, target);
+                    // if we have a negative offset, add initial padding to the size of the offset
+                    if lead > 0 {
+                        file.set_len(lead).unwrap();
+                    }
+                    // write the input to the output, only the part that ends up in the target
+                    copy_at(&mut sourcefile, source_start, &mut file, lead, length, &name, &mut progress);
+                    // adjust the length to the right target length
+                    file.set_len(target.size).unwrap();
                 }
-                // write the input to the output
-                let mut buf = Vec::new();
-                sourcefile.read_to_end(&mut buf).expect("Unable to read input file");
-                file.write_all(&buf).expect("Unable to write to output file");
-                // adjust the length to the right target length
-                file.set_len(target.size).unwrap();
             },
             None => {
-                // no mapping, just expand the filesize to target size, this has to advantage to reserve the disk space
+                // no mapping, just expand the filesize to target size, the file stays sparse unless it is preallocated
                 file.set_len(target.size).unwrap();
             }
         }
+        // allocating after writing only fills the holes, the written data and shared extents are kept as they are
+        if preallocate {
+            if let Err(e) = space::preallocate(&file, target.size) {
+                println!("  {}: unable to preallocate ({})", name, e);
+            }
+        }
+        fs::rename(&partial, &path).expect("Unable to rename finished file");
     }
-    println!("Migration complete!");
-}
\ No newline at end of file
+    progress
+}
+
+// write the output in a staging directory and move it into place when every file was written, an error or
+// interruption removes the staging directory so no partial output is left, returns whether the output is complete
+// the partial output of an interrupted run that is resumed becomes the staging directory
+pub fn migrate(torrent_meta:&Metainfo, inputs: &mut [SourceFile], targets: &mut [TargetFile], output: &str, plan:&Plan, resume:Option<&Path>, preallocate:bool) -> bool {
+    let staging = Staging::create(Path::new(output), resume).expect("Unable to create staging directory");
+    staging::set_writing(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| write_files(torrent_meta, inputs, targets, staging.path(), plan, preallocate)));
+    staging::set_writing(false);
+    let progress = match result {
+        Ok(p) => p,
+        Err(payload) => {
+            drop(staging);
+            if payload.is::<Interrupted>() {
+                println!("\nMigration interrupted, the partial output has been removed");
+            } else {
+                println!("Migration failed, the partial output has been removed");
+            }
+            return false;
+        }
+    };
+    let files:Vec<PathBuf> = targets.iter().map(|t| t.path.clone()).collect();
+    staging.commit(Path::new(output), torrent_meta.info().directory(), &files).expect("Unable to move the output into place");
+    println!("Migration complete! {:.1} MB written at {:.1} MB/s, {:.1} MB shared with the input", progress.written as f64 / 1e6,
+        rate(progress.written, progress.started) / 1e6, progress.shared as f64 / 1e6);
+    true
+}
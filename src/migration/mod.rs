@@ -2,21 +2,68 @@ extern crate bip_metainfo;
 extern crate walkdir;
 extern crate sha1;
 extern crate rayon;
+extern crate claxon;
+extern crate libc;
+extern crate ctrlc;
 use self::bip_metainfo::{Metainfo};
 use self::walkdir::{DirEntry, WalkDir};
 use std::path::{PathBuf,Path};
 use std::collections::{HashSet};
 use std::io;
+use std::io::Write;
 use std::fs;
+use std::env;
+use std::process;
 mod filemapping;
 mod matching;
 mod migrator;
+mod flac;
+mod layout;
+mod verify;
+mod cache;
+mod table;
+mod search;
+mod reencode;
+mod repair;
+mod packing;
+mod variant;
+mod overlay;
+mod mp4;
+mod riff;
+mod ogg;
+mod frames;
+mod reflink;
+mod source;
+mod inplace;
+mod staging;
+mod resume;
+mod merge;
+mod space;
 
-static AUDIO_FORMATS:&'static[&'static str] = &["flac","mp3","ogg","aac","ac3","dts"];
+static AUDIO_FORMATS:&[&str] = &["flac","mp3","ogg","aac","ac3","dts","m4a","wav","aiff","opus"];
 
 pub type MigrationError = Box<::std::error::Error>;
 
-#[derive(Debug)]
+// how the output files are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Copy,
+    Hardlink, // byte-identical files are hardlinked to their input instead of copied
+    Reflink, // data is cloned from the input where the filesystem supports it
+}
+
+// the options of a migration run
+pub struct Options {
+    pub cache_dir: Option<PathBuf>, // where piece results are cached between runs
+    pub source_torrent: Option<Vec<u8>>, // the torrent the input was originally downloaded with
+    pub strategy: Strategy,
+    pub in_place: bool, // transform the input itself instead of writing the output
+    pub merge: bool, // write verified pieces into a download in progress in the output
+    pub verified_only: bool, // only write the data of pieces that verified
+    pub preallocate: bool, // allocate the output files completely
+}
+
+#[derive(Debug, Clone)]
 pub struct SourceFile {
     path: PathBuf,
     display: String,
@@ -24,9 +71,13 @@ pub struct SourceFile {
     is_audio: bool,
     size: u64,
     mapping: Option<usize>, // this holds which target file this maps to
+    derived: bool, // transformed data of another input, in a temporary file or as segments of the other input
+    segments: Option<Vec<overlay::Segment>>, // the data of a derived input that reads from the file of the other input
+    original: Option<PathBuf>, // the path in the source torrent
+    verified: Option<bool>, // whether the input is bit-perfect against the source torrent
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TargetFile {
     index: usize,
     path: PathBuf,
@@ -37,6 +88,109 @@ pub struct TargetFile {
     offset: i64,
 }
 
+// write transformed data of an input to a temporary file and map it in place of the original, returns the new input index
+pub fn add_derived_input(inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], source:usize, data:&[u8], label:&str) -> Result<usize, MigrationError> {
+    let mut path = env::temp_dir();
+    path.push(format!("mtmigrate-{}-{}", process::id(), inputs.len()));
+    if let Some(ref e) = inputs[source].extension {
+        path.set_extension(e);
+    }
+    staging::add_temporary_file(&path);
+    let mut file = fs::File::create(&path)?;
+    file.write_all(data)?;
+    let derived = SourceFile {
+        path,
+        display: format!("{} ({})", inputs[source].display, label),
+        extension: inputs[source].extension.clone(),
+        is_audio: inputs[source].is_audio,
+        size: data.len() as u64,
+        mapping: None,
+        derived: true,
+        segments: None,
+        original: inputs[source].original.clone(),
+        verified: inputs[source].verified,
+    };
+    Ok(map_derived_input(inputs, targets, source, derived))
+}
+
+// an input that transforms the data of another input with the given segments, it reads from the file of the other input
+// when the other input is derived itself the segments are translated to the file it reads from
+pub fn overlay_input(source:&SourceFile, segments:Vec<overlay::Segment>, label:&str) -> SourceFile {
+    let segments = match source.segments {
+        Some(ref inner) => overlay::compose(&segments, inner),
+        None => segments,
+    };
+    SourceFile {
+        path: source.path.clone(),
+        display: format!("{} ({})", source.display, label),
+        extension: source.extension.clone(),
+        is_audio: source.is_audio,
+        size: overlay::size(&segments),
+        mapping: None,
+        derived: true,
+        segments: Some(segments),
+        original: source.original.clone(),
+        verified: source.verified,
+    }
+}
+
+// map a derived input in place of the input it was derived from, returns the new input index
+pub fn map_derived_input(inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], source:usize, mut derived:SourceFile) -> usize {
+    let index = inputs.len();
+    derived.mapping = inputs[source].mapping;
+    if let Some(m) = inputs[source].mapping {
+        targets[m].mapping = Some(index);
+    }
+    inputs[source].mapping = None;
+    inputs.push(derived);
+    index
+}
+
+// map an input in place of another one whose data it transforms with the given segments, nothing is written
+pub fn add_overlay_input(inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], source:usize, segments:Vec<overlay::Segment>, label:&str) -> usize {
+    let derived = overlay_input(&inputs[source], segments, label);
+    map_derived_input(inputs, targets, source, derived)
+}
+
+// undo a derived input, mapping the original input again
+pub fn remove_derived_input(inputs:&mut [SourceFile], targets:&mut [TargetFile], source:usize, derived:usize) {
+    if let Some(m) = inputs[derived].mapping {
+        targets[m].mapping = Some(source);
+    }
+    inputs[source].mapping = inputs[derived].mapping;
+    inputs[derived].mapping = None;
+    if inputs[derived].segments.is_none() {
+        staging::remove_temporary_file(&inputs[derived].path);
+    }
+}
+
+// write the mapped inputs that only exist as segments to temporary files, for when they can't be read through the segments
+fn write_overlay_inputs(inputs:&mut [SourceFile]) -> Result<(), MigrationError> {
+    for (index, input) in inputs.iter_mut().enumerate() {
+        if input.segments.is_none() || input.mapping.is_none() {
+            continue;
+        }
+        let mut path = env::temp_dir();
+        path.push(format!("mtmigrate-{}-{}", process::id(), index));
+        if let Some(ref e) = input.extension {
+            path.set_extension(e);
+        }
+        staging::add_temporary_file(&path);
+        let mut file = fs::File::create(&path)?;
+        io::copy(&mut overlay::open(input)?, &mut file)?;
+        input.path = path;
+        input.segments = None;
+    }
+    Ok(())
+}
+
+// remove the temporary files of all derived inputs
+fn cleanup_derived_inputs(inputs:&[SourceFile]) {
+    for input in inputs.iter().filter(|i| i.derived && i.segments.is_none()) {
+        staging::remove_temporary_file(&input.path);
+    }
+}
+
 // ignore all results that are not a file
 fn is_file(entry: &Result<DirEntry, self::walkdir::Error>) -> bool {
     match *entry {
@@ -45,7 +199,24 @@ fn is_file(entry: &Result<DirEntry, self::walkdir::Error>) -> bool {
     }
 }
 
-pub fn run<B>(buffer: B, input: &str, output: &str) -> Result<(),MigrationError> 
+// the files of a torrent
+fn torrent_targets(torrent_meta:&Metainfo, audio_formats:&HashSet<String>) -> Vec<TargetFile> {
+    let mut targets = Vec::new();
+    let torrent_info = &torrent_meta.info();
+    for (i, file) in torrent_info.files().enumerate() {
+        let path = file.path();
+        let extension = path.extension().map(|e| e.to_string_lossy().into_owned());
+        let is_audio = if let Some(ref i) = extension {
+            audio_formats.contains(i)
+        } else {
+            false
+        };
+        targets.push(TargetFile { index:i, path:path.to_path_buf(), extension, is_audio, size:file.length(), mapping:None, offset:0 });
+    }
+    targets
+}
+
+pub fn run<B>(buffer: B, input: &str, output: &str, options: Options) -> Result<(),MigrationError> 
     where B: AsRef<[u8]> {
     // build the set of audio formats
     let audio_formats:HashSet<String> = AUDIO_FORMATS.into_iter().map(|x| x.to_string()).collect();
@@ -53,6 +224,16 @@ pub fn run<B>(buffer: B, input: &str, output: &str) -> Result<(),MigrationError>
     // extract the metadata
     let torrent_meta = Metainfo::from_bytes(&buffer).expect("Failed to parse torrent file");
 
+    // an interrupted in-place migration has to be finished or rolled back before the input can be used again
+    if inplace::has_journal(Path::new(input)) {
+        inplace::recover(Path::new(input));
+        return Ok(());
+    }
+
+    // Ctrl-C while the output is written removes the partial output
+    staging::install_handler();
+    let staged = if options.in_place || options.merge { None } else { staging::offer_cleanup(Path::new(output)) };
+
     // get files (recursively) from the input directory
     let mut inputs = Vec::new();
     let walker = WalkDir::new(input).into_iter();
@@ -71,27 +252,20 @@ pub fn run<B>(buffer: B, input: &str, output: &str) -> Result<(),MigrationError>
         };
         let display = path.strip_prefix(input).unwrap().to_string_lossy().into_owned();
         //let is_audio =  extension.is_some() && audio_formats.cont
-        inputs.push(SourceFile { path:path.to_path_buf(), display, extension, is_audio, size, mapping:None });
+        inputs.push(SourceFile { path:path.to_path_buf(), display, extension, is_audio, size, mapping:None, derived:false, segments:None, original:None, verified:None });
     }
     // sort these files so they are easier to use
     inputs.sort_by(|a, b| a.path.cmp(&b.path));
 
     // get the target files from the torrent metadata
-    let mut targets = Vec::new();
-    {
-        let torrent_info = &torrent_meta.info();
-        for (i, file) in torrent_info.files().enumerate() {
-            let path = file.path();
-            let extension = match path.extension() {
-                Some(e) => Some(e.to_string_lossy().into_owned()),
-                None => None
-            };
-            let is_audio = if let Some(ref i) = extension {
-                audio_formats.contains(i)
-            } else {
-                false
-            };
-            targets.push(TargetFile { index:i, path:path.to_path_buf(), extension, is_audio, size:file.length(), mapping:None, offset:0 });
+    let mut targets = torrent_targets(&torrent_meta, &audio_formats);
+
+    // verify the inputs against the source torrent first
+    if let Some(ref source_buffer) = options.source_torrent {
+        let source_meta = Metainfo::from_bytes(source_buffer).expect("Failed to parse source torrent file");
+        let mut source_targets = torrent_targets(&source_meta, &audio_formats);
+        if !source::check_source(&source_meta, &mut source_targets, &mut inputs, options.cache_dir.clone()) {
+            return Ok(());
         }
     }
 
@@ -102,18 +276,85 @@ pub fn run<B>(buffer: B, input: &str, output: &str) -> Result<(),MigrationError>
     }
     filemapping::create_mapping(&mut inputs, &mut targets);
 
-    // run the matcher
-    matching::run_matcher(&torrent_meta, &mut inputs, &mut targets);
+    // run the matcher, reusing the piece results of earlier runs
+    let piece_cache = cache::PieceCache::load(options.cache_dir, &torrent_meta);
+    let mut table = table::PieceTable::new(&torrent_meta, &piece_cache);
+    matching::run_matcher(&torrent_meta, &mut inputs, &mut targets, &mut table);
+    piece_cache.save();
 
-    // run the migrator
+    if options.in_place {
+        println!("In-place migration rewrites the files in '{}', they will no longer match the old torrent. Migrate in place? (y/n) [n]", input);
+        let mut reply = String::new();
+        io::stdin().read_line(&mut reply).unwrap();
+        match reply.trim() {
+            "y" | "yes" => {
+                // temporary files are kept when the migration stopped, it needs them to resume
+                write_overlay_inputs(&mut inputs)?;
+                staging::keep_temporary_files();
+                if !inplace::migrate(Path::new(input), &inputs, &targets) {
+                    return Ok(());
+                }
+            },
+            _ => {
+                // do nothing
+            }
+        }
+        cleanup_derived_inputs(&inputs);
+        return Ok(());
+    }
+
+    if options.merge {
+        println!("Merge the input into the download in '{}'? Pause the torrent in your client first. (y/n) [n]", output);
+        let mut reply = String::new();
+        io::stdin().read_line(&mut reply).unwrap();
+        match reply.trim() {
+            "y" | "yes" => {
+                merge::merge(&torrent_meta, &piece_cache, &inputs, &targets, &table, Path::new(output));
+            },
+            _ => {
+                // do nothing
+            }
+        }
+        cleanup_derived_inputs(&inputs);
+        return Ok(());
+    }
+
+    // run the migrator, showing what it will write first
+    // only files of which every piece verifies can be hardlinked to the input
+    let complete:Vec<bool> = (0..targets.len()).map(|t| table.file_complete(t)).collect();
+    // files that an earlier run of this torrent already wrote are verified and kept when they are complete
+    let existing = resume::existing_files(&torrent_meta, &targets, Path::new(output), staged.as_deref());
+    let resuming = existing.iter().any(|e| e.is_some());
+    let kept = if resuming {
+        resume::check_existing(&torrent_meta, &piece_cache, &inputs, &targets, &existing)
+    } else {
+        vec![false; targets.len()]
+    };
+    let verified = if options.verified_only {
+        Some((0..targets.len()).map(|t| table.verified_ranges(t)).collect())
+    } else {
+        None
+    };
+    let plan = migrator::plan(&inputs, &targets, output, &complete, &kept, verified, options.strategy);
+    if resuming {
+        resume::print_resume(&targets, &existing, &kept, &plan);
+    }
+    migrator::print_plan(&plan, &targets);
+    if !migrator::check_space(&plan, &targets, output, options.preallocate) {
+        cleanup_derived_inputs(&inputs);
+        return Ok(());
+    }
     // ask to execute the migration
     println!("Run this migration? (y/n) [y]");
     let mut reply = String::new();
     io::stdin().read_line(&mut reply).unwrap();
     match reply.trim() {
         "y" | "yes" | "" => {
-            migrator::migrate(&torrent_meta, &mut inputs, &mut targets, output);
-            // offer delete
+            // only offer to delete the input when the output is complete
+            if !migrator::migrate(&torrent_meta, &mut inputs, &mut targets, output, &plan, staged.as_deref(), options.preallocate) {
+                cleanup_derived_inputs(&inputs);
+                return Ok(());
+            }
             println!("Permanently delete input folder '{}'? (y/n) [n]", input);
             let mut re = String::new();
             io::stdin().read_line(&mut re).unwrap();
@@ -130,6 +371,7 @@ pub fn run<B>(buffer: B, input: &str, output: &str) -> Result<(),MigrationError>
             // do nothing
         }
     }
+    cleanup_derived_inputs(&inputs);
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,204 @@
+use ::migration::SourceFile;
+use migration::overlay;
+use migration::overlay::Segment;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+// atoms that only hold other atoms, on the path to the chunk offset tables
+static CONTAINERS:&[&[u8;4]] = &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"edts", b"dinf", b"mvex"];
+
+// the moov atom is rewritten in memory, a larger one isn't a plausible audio file
+const MAX_MOOV:u64 = 64 << 20;
+
+#[derive(Debug, Clone)]
+struct Atom {
+    kind: [u8; 4],
+    start: u64,
+    header: u64,
+    size: u64,
+}
+
+impl Atom {
+    fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    fn is(&self, kind:&[u8;4]) -> bool {
+        &self.kind == kind
+    }
+
+    fn is_free(&self) -> bool {
+        self.is(b"free") || self.is(b"skip")
+    }
+}
+
+fn read_u32(data:&[u8], position:usize) -> u64 {
+    data[position..position+4].iter().fold(0u64, |v, b| (v << 8) | *b as u64)
+}
+
+fn read_u64(data:&[u8], position:usize) -> u64 {
+    data[position..position+8].iter().fold(0u64, |v, b| (v << 8) | *b as u64)
+}
+
+fn write_uint(data:&mut [u8], position:usize, bytes:usize, value:u64) {
+    for i in 0..bytes {
+        data[position + i] = (value >> (8 * (bytes - 1 - i))) as u8;
+    }
+}
+
+// parse the atoms in a range of the data, only the atom headers are read
+// None when the range isn't made up of valid atoms
+fn parse_atoms<R:Read + Seek>(reader:&mut R, start:u64, end:u64) -> Option<Vec<Atom>> {
+    let mut atoms = Vec::new();
+    let mut position = start;
+    while position < end {
+        if position + 8 > end {
+            return None;
+        }
+        let mut header = [0u8; 16];
+        reader.seek(SeekFrom::Start(position)).ok()?;
+        reader.read_exact(&mut header[..8]).ok()?;
+        let mut kind = [0u8; 4];
+        kind.copy_from_slice(&header[4..8]);
+        let (size, header_size) = match read_u32(&header, 0) {
+            0 => (end - position, 8), // extends to the end of the file
+            1 => {
+                if position + 16 > end {
+                    return None;
+                }
+                reader.read_exact(&mut header[8..16]).ok()?;
+                (read_u64(&header, 8), 16)
+            },
+            s => (s, 8),
+        };
+        // the size comes from the file, it can be anything
+        if size < header_size || position.checked_add(size).is_none_or(|e| e > end) {
+            return None;
+        }
+        atoms.push(Atom { kind, start:position, header:header_size, size });
+        position += size;
+    }
+    Some(atoms)
+}
+
+// find the chunk offset tables (stco and co64) inside an atom of the moov data
+fn chunk_offset_tables(moov:&mut Cursor<&[u8]>, atom:&Atom, tables:&mut Vec<Atom>) {
+    if atom.is(b"stco") || atom.is(b"co64") {
+        tables.push(atom.clone());
+    } else if CONTAINERS.iter().any(|c| atom.is(c)) {
+        if let Some(children) = parse_atoms(moov, atom.start + atom.header, atom.end()) {
+            for child in children {
+                chunk_offset_tables(moov, &child, tables);
+            }
+        }
+    }
+}
+
+// describe the top level atoms in the given order, only the moov atom is rewritten with the chunk offsets
+// moved along with the data they point to, the other atoms are ranges of the input
+fn rebuild(data:&[u8], order:&[&Atom], tables:&[Atom], moov:&Atom) -> Option<Vec<Segment>> {
+    let mut moved = HashMap::new();
+    let mut position = 0;
+    for atom in order {
+        moved.insert(atom.start, position);
+        position += atom.size;
+    }
+    let relocate = |offset:u64| -> u64 {
+        order.iter().find(|a| a.start <= offset && offset < a.end())
+            .map_or(offset, |a| offset - a.start + moved[&a.start])
+    };
+    let mut patched = data.to_vec();
+    for table in tables {
+        let position = (table.start + table.header) as usize;
+        if position + 8 > table.end() as usize {
+            return None;
+        }
+        let entries = read_u32(&patched, position + 4) as usize;
+        let width = if table.is(b"co64") { 8 } else { 4 };
+        if position + 8 + entries * width > table.end() as usize {
+            return None;
+        }
+        for e in 0..entries {
+            let entry = position + 8 + e * width;
+            let offset = if width == 8 { read_u64(&patched, entry) } else { read_u32(&patched, entry) };
+            let relocated = relocate(offset);
+            if width == 4 && relocated > u32::MAX as u64 {
+                return None;
+            }
+            write_uint(&mut patched, entry, width, relocated);
+        }
+    }
+
+    let mut segments = Vec::new();
+    let mut patched = Some(patched);
+    for atom in order {
+        if atom.start == moov.start {
+            segments.push(Segment::Data(patched.take()?));
+            continue;
+        }
+        // neighbouring atoms that stay together are read as one range
+        if let Some(&mut Segment::File(ref mut range)) = segments.last_mut() {
+            if range.end == atom.start {
+                range.end = atom.end();
+                continue;
+            }
+        }
+        segments.push(Segment::File(atom.start..atom.end()));
+    }
+    Some(segments)
+}
+
+// alternative layouts of an MP4 file: the moov atom before or after the media data, with and without
+// free atoms, the chunk offsets are fixed up for every layout
+// a layout only describes which ranges of the input make up the file and holds the rewritten moov atom
+pub fn layouts(input:&SourceFile) -> Vec<(String, Vec<Segment>)> {
+    let mut layouts = Vec::new();
+    let mut file = match overlay::open(input) {
+        Ok(f) => f,
+        Err(_) => return layouts,
+    };
+    let atoms = match parse_atoms(&mut file, 0, input.size) {
+        Some(a) => a,
+        None => return layouts,
+    };
+    let moov = match atoms.iter().find(|a| a.is(b"moov")) {
+        Some(m) => m,
+        None => return layouts,
+    };
+    if !atoms.iter().any(|a| a.is(b"mdat")) || moov.size > MAX_MOOV {
+        return layouts;
+    }
+    let mut data = vec![0u8; moov.size as usize];
+    if file.seek(SeekFrom::Start(moov.start)).and_then(|_| file.read_exact(&mut data)).is_err() {
+        return layouts;
+    }
+    // the tables are found in the moov data, their positions are relative to the moov atom
+    let mut tables = Vec::new();
+    let local = Atom { start:0, ..moov.clone() };
+    chunk_offset_tables(&mut Cursor::new(&data[..]), &local, &mut tables);
+
+    for &moov_first in &[true, false] {
+        for &keep_free in &[true, false] {
+            let mut order:Vec<&Atom> = atoms.iter().filter(|a| !a.is(b"moov") && (keep_free || !a.is_free())).collect();
+            let position = if moov_first {
+                order.iter().position(|a| a.is(b"mdat")).unwrap()
+            } else {
+                order.iter().rposition(|a| a.is(b"mdat")).unwrap() + 1
+            };
+            order.insert(position, moov);
+            let unchanged = order.len() == atoms.len() && order.iter().zip(atoms.iter()).all(|(a, b)| a.start == b.start);
+            if unchanged {
+                continue;
+            }
+            let layout = match rebuild(&data, &order, &tables, moov) {
+                Some(l) => l,
+                None => continue,
+            };
+            if !layouts.iter().any(|l:&(String, Vec<Segment>)| l.1 == layout) {
+                let label = format!("moov {}, {} free atoms", if moov_first { "first" } else { "last" }, if keep_free { "with" } else { "without" });
+                layouts.push((label, layout));
+            }
+        }
+    }
+    layouts
+}
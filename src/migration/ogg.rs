@@ -0,0 +1,163 @@
+use ::migration::SourceFile;
+use migration::overlay;
+use migration::overlay::Segment;
+use std::io::{BufReader, Read};
+
+// how many pages the comment header may have grown or shrunk by
+pub const MAX_PAGE_DELTA:i64 = 8;
+
+// the CRC used by Ogg pages: polynomial 0x04c11db7, not reflected, initial value 0
+fn crc_table() -> Vec<u32> {
+    (0..256u32).map(|i| {
+        let mut crc = i << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+        crc
+    }).collect()
+}
+
+fn crc(table:&[u32], data:&[u8]) -> u32 {
+    data.iter().fold(0u32, |crc, b| (crc << 8) ^ table[(((crc >> 24) as u8) ^ *b) as usize])
+}
+
+fn read_u32(data:&[u8], position:usize) -> u32 {
+    data[position..position+4].iter().rev().fold(0u32, |v, b| (v << 8) | *b as u32)
+}
+
+fn write_u32(data:&mut [u8], position:usize, value:u32) {
+    for i in 0..4 {
+        data[position + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+// read the next page of a stream, None at the end of the stream or when it isn't a clean page stream
+fn read_page<R:Read>(reader:&mut R, page:&mut Vec<u8>) -> Option<bool> {
+    page.resize(27, 0);
+    match reader.read(&mut page[..1]) {
+        Ok(0) => return Some(false),
+        Ok(_) => {},
+        Err(_) => return None,
+    }
+    reader.read_exact(&mut page[1..27]).ok()?;
+    if &page[0..4] != b"OggS" {
+        return None;
+    }
+    let segments = page[26] as usize;
+    page.resize(27 + segments, 0);
+    reader.read_exact(&mut page[27..]).ok()?;
+    let body:usize = page[27..].iter().map(|s| *s as usize).sum();
+    page.resize(27 + segments + body, 0);
+    reader.read_exact(&mut page[27 + segments..]).ok()?;
+    Some(true)
+}
+
+// renumber the audio pages as if the headers took delta more pages, the header pages are the ones
+// with a granule position of 0, every renumbered page gets a new CRC
+// the stream is read page by page, the result only holds the new sequence number and CRC of every page
+pub fn renumber(input:&SourceFile, delta:i64) -> Option<Vec<Segment>> {
+    let mut reader = BufReader::new(overlay::open(input).ok()?);
+    let table = crc_table();
+    let mut page = Vec::new();
+    let mut position = 0;
+    let mut audio = false;
+    let mut changes = Vec::new();
+    while read_page(&mut reader, &mut page)? {
+        audio = audio || page[6..14].iter().any(|b| *b != 0);
+        if audio {
+            let sequence = read_u32(&page, 18) as i64 + delta;
+            if sequence < 0 || sequence > u32::MAX as i64 {
+                return None;
+            }
+            write_u32(&mut page, 18, sequence as u32);
+            write_u32(&mut page, 22, 0);
+            let crc = crc(&table, &page);
+            write_u32(&mut page, 22, crc);
+            changes.push((position + 18, page[18..26].to_vec()));
+        }
+        position += page.len() as u64;
+    }
+    if !audio {
+        return None;
+    }
+    Some(overlay::patch(input.size, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::migration::{SourceFile, overlay_input};
+    use migration::overlay;
+    use super::{crc, crc_table, read_u32, renumber, write_u32};
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::process;
+
+    // a page with one segment of body, a granule position of 0 marks a header page
+    fn page(granule:u8, sequence:u32, body:&[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend(&[0, 0, granule, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        page.extend(&[0; 8]);
+        page.extend(&[1, body.len() as u8]);
+        page.extend(body);
+        write_u32(&mut page, 18, sequence);
+        let crc = crc(&crc_table(), &page);
+        write_u32(&mut page, 22, crc);
+        page
+    }
+
+    fn test_input(name:&str, data:&[u8]) -> SourceFile {
+        let path = env::temp_dir().join(format!("mtmigrate-test-{}-{}", name, process::id()));
+        fs::write(&path, data).unwrap();
+        SourceFile {
+            path,
+            display: name.to_string(),
+            extension: Some("ogg".to_string()),
+            is_audio: true,
+            size: data.len() as u64,
+            mapping: None,
+            derived: false,
+            segments: None,
+            original: None,
+            verified: None,
+        }
+    }
+
+    fn read_renumbered(input:&SourceFile, delta:i64) -> Vec<u8> {
+        let derived = overlay_input(input, renumber(input, delta).unwrap(), "renumbered");
+        let mut data = Vec::new();
+        overlay::open(&derived).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn page_crc() {
+        assert_eq!(crc(&crc_table(), b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn renumber_audio_pages() {
+        let pages = [page(0, 0, b"head"), page(0, 1, b"comments"), page(1, 2, b"audio"), page(2, 3, b"more audio")];
+        let input = test_input("ogg", &pages.concat());
+        let renumbered = read_renumbered(&input, 2);
+        assert!(renumber(&input, -3).is_none());
+        fs::remove_file(&input.path).unwrap();
+
+        // the header pages are untouched, the audio pages are shifted and have a valid CRC again
+        let expected = [pages[0].clone(), pages[1].clone(), page(1, 4, b"audio"), page(2, 5, b"more audio")];
+        assert_eq!(renumbered, expected.concat());
+        let mut audio = expected[3].clone();
+        let crc_field = read_u32(&audio, 22);
+        write_u32(&mut audio, 22, 0);
+        assert_eq!(crc(&crc_table(), &audio), crc_field);
+    }
+
+    #[test]
+    fn renumber_needs_clean_pages() {
+        let mut data = page(0, 0, b"head");
+        data.extend(b"not a page at all, only some bytes");
+        let input = test_input("ogg-damaged", &data);
+        assert!(renumber(&input, 1).is_none());
+        fs::remove_file(&input.path).unwrap();
+    }
+}
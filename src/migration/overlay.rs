@@ -0,0 +1,247 @@
+use ::migration::SourceFile;
+use migration::sha1::{Sha1, Digest};
+use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+// a part of the data of a derived input, a range of the file it was derived from or data of its own
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    File(Range<u64>),
+    Data(Vec<u8>),
+}
+
+impl Segment {
+    fn length(&self) -> u64 {
+        match *self {
+            Segment::File(ref range) => range.end - range.start,
+            Segment::Data(ref data) => data.len() as u64,
+        }
+    }
+}
+
+pub fn size(segments:&[Segment]) -> u64 {
+    segments.iter().map(|s| s.length()).sum()
+}
+
+// the segments of a file with some of its bytes replaced, the changes are in ascending order and don't overlap
+pub fn patch(size:u64, changes:Vec<(u64, Vec<u8>)>) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut position = 0;
+    for (start, bytes) in changes {
+        if start > position {
+            segments.push(Segment::File(position..start));
+        }
+        position = start + bytes.len() as u64;
+        segments.push(Segment::Data(bytes));
+    }
+    if position < size {
+        segments.push(Segment::File(position..size));
+    }
+    segments
+}
+
+// segments that describe data derived from a derived input in terms of the file that one was derived from
+pub fn compose(outer:&[Segment], inner:&[Segment]) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for segment in outer {
+        let range = match *segment {
+            Segment::File(ref r) => r.clone(),
+            Segment::Data(ref d) => {
+                segments.push(Segment::Data(d.clone()));
+                continue;
+            }
+        };
+        let mut start = 0;
+        for part in inner {
+            let end = start + part.length();
+            if end > range.start && start < range.end {
+                let from = range.start.max(start) - start;
+                let to = min(range.end, end) - start;
+                segments.push(match *part {
+                    Segment::File(ref r) => Segment::File(r.start + from..r.start + to),
+                    Segment::Data(ref d) => Segment::Data(d[from as usize..to as usize].to_vec()),
+                });
+            }
+            start = end;
+        }
+    }
+    segments
+}
+
+// identifies the derived data for the piece cache
+pub fn digest(segments:&[Segment]) -> String {
+    let mut hasher = Sha1::new();
+    for segment in segments {
+        match *segment {
+            Segment::File(ref range) => hasher.input(format!("file {} {}\n", range.start, range.end).as_bytes()),
+            Segment::Data(ref data) => {
+                hasher.input(format!("data {}\n", data.len()).as_bytes());
+                hasher.input(data);
+            }
+        }
+    }
+    hasher.result().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// reads the data of an input, through its segments when it is derived from another file
+pub struct InputFile<'a> {
+    file: File,
+    segments: Option<&'a [Segment]>,
+    starts: Vec<u64>, // where every segment starts in the derived data
+    size: u64,
+    position: u64,
+    file_position: u64, // avoids a seek for every read of consecutive data
+}
+
+pub fn open<'a>(input:&'a SourceFile) -> io::Result<InputFile<'a>> {
+    let file = File::open(&input.path)?;
+    let segments = input.segments.as_deref();
+    let mut starts = Vec::new();
+    let mut size = 0;
+    for segment in segments.unwrap_or(&[]) {
+        starts.push(size);
+        size += segment.length();
+    }
+    if segments.is_none() {
+        size = file.metadata()?.len();
+    }
+    Ok(InputFile { file, segments, starts, size, position:0, file_position:0 })
+}
+
+impl<'a> InputFile<'a> {
+    // the file that is read, only of use for the data of an input that isn't derived
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl<'a> Read for InputFile<'a> {
+    fn read(&mut self, buffer:&mut [u8]) -> io::Result<usize> {
+        let segments = match self.segments {
+            Some(s) => s,
+            None => {
+                let read = self.file.read(buffer)?;
+                self.position += read as u64;
+                return Ok(read);
+            }
+        };
+        if self.position >= self.size || buffer.is_empty() {
+            return Ok(0);
+        }
+        let index = match self.starts.binary_search(&self.position) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let within = self.position - self.starts[index];
+        let length = min(buffer.len() as u64, segments[index].length() - within) as usize;
+        let read = match segments[index] {
+            Segment::File(ref range) => {
+                let position = range.start + within;
+                if self.file_position != position {
+                    self.file.seek(SeekFrom::Start(position))?;
+                }
+                let read = self.file.read(&mut buffer[..length])?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input file got shorter"));
+                }
+                self.file_position = position + read as u64;
+                read
+            },
+            Segment::Data(ref data) => {
+                buffer[..length].copy_from_slice(&data[within as usize..within as usize + length]);
+                length
+            },
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a> Seek for InputFile<'a> {
+    fn seek(&mut self, from:SeekFrom) -> io::Result<u64> {
+        if self.segments.is_none() {
+            self.position = self.file.seek(from)?;
+            return Ok(self.position);
+        }
+        let position = match from {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(d) => self.position as i64 + d,
+            SeekFrom::End(d) => self.size as i64 + d,
+        };
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the input"));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::migration::{SourceFile, overlay_input};
+    use super::{Segment, compose, open, patch, size};
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::process;
+
+    fn data(bytes:&[u8]) -> Segment {
+        Segment::Data(bytes.to_vec())
+    }
+
+    #[test]
+    fn patch_replaces_bytes() {
+        let segments = patch(10, vec![(0, b"ab".to_vec()), (5, b"c".to_vec()), (8, b"de".to_vec())]);
+        assert_eq!(segments, vec![data(b"ab"), Segment::File(2..5), data(b"c"), Segment::File(6..8), data(b"de")]);
+        assert_eq!(size(&segments), 10);
+        assert_eq!(patch(10, vec![]), vec![Segment::File(0..10)]);
+    }
+
+    #[test]
+    fn compose_translates_ranges() {
+        // the inner input is the file with a block of data in the middle
+        let inner = vec![Segment::File(100..110), data(b"0123456789"), Segment::File(200..210)];
+        let outer = vec![Segment::File(5..25), data(b"x"), Segment::File(0..2)];
+        assert_eq!(compose(&outer, &inner), vec![
+            Segment::File(105..110), data(b"0123456789"), Segment::File(200..205), data(b"x"), Segment::File(100..102),
+        ]);
+        assert_eq!(compose(&[Segment::File(12..14)], &inner), vec![data(b"23")]);
+    }
+
+    #[test]
+    fn read_through_segments() {
+        let path = env::temp_dir().join(format!("mtmigrate-test-overlay-{}", process::id()));
+        fs::write(&path, b"abcdefghij").unwrap();
+        let input = SourceFile {
+            path: path.clone(),
+            display: "input".to_string(),
+            extension: None,
+            is_audio: false,
+            size: 10,
+            mapping: None,
+            derived: false,
+            segments: None,
+            original: None,
+            verified: None,
+        };
+        let derived = overlay_input(&input, patch(10, vec![(2, b"XY".to_vec())]), "patched");
+        let twice = overlay_input(&derived, vec![Segment::File(1..5), data(b"-"), Segment::File(8..10)], "cut");
+        assert_eq!(twice.segments, Some(vec![Segment::File(1..2), data(b"XY"), Segment::File(4..5), data(b"-"), Segment::File(8..10)]));
+
+        let mut file = open(&twice).unwrap();
+        let mut all = Vec::new();
+        file.read_to_end(&mut all).unwrap();
+        assert_eq!(all, b"bXYe-ij");
+        let mut part = [0u8; 3];
+        file.seek(SeekFrom::Start(2)).unwrap();
+        file.read_exact(&mut part).unwrap();
+        assert_eq!(&part, b"Ye-");
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.read_exact(&mut part[..1]).unwrap();
+        assert_eq!(part[0], b'j');
+        fs::remove_file(&path).unwrap();
+    }
+}
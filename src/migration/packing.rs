@@ -0,0 +1,142 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::bip_metainfo::{Metainfo};
+use migration::filemapping;
+use migration::layout::TorrentLayout;
+use migration::table::PieceTable;
+use migration::verify;
+use migration::sha1::{Sha1, Digest};
+use migration::rayon::prelude::*;
+use std::collections::BTreeSet;
+
+// inputs tried per small file and combinations tried per piece, enough for a typical release
+const MAX_CANDIDATES:usize = 6;
+const MAX_COMBINATIONS:usize = 1 << 16;
+
+// small files don't have pieces of their own, they can only be confirmed by the pieces they share
+fn is_small(layout:&TorrentLayout, targets:&[TargetFile], file:usize) -> bool {
+    !targets[file].is_audio && !layout.is_empty(file) && layout.interior_pieces(file).start == layout.interior_pieces(file).end
+}
+
+// plausible inputs for a small target, the current mapping first, then by size, extension and name
+fn candidates(inputs:&[SourceFile], target:&TargetFile, allowed:&BTreeSet<usize>) -> Vec<usize> {
+    let name = target.path.file_name();
+    let mut candidates:Vec<usize> = (0..inputs.len()).filter(|i| {
+        let input = &inputs[*i];
+        !input.is_audio && !input.derived && input.mapping.is_none_or(|m| allowed.contains(&m)) &&
+            (input.size == target.size || input.extension == target.extension || input.path.file_name() == name)
+    }).collect();
+    candidates.sort_by_key(|i| {
+        let input = &inputs[*i];
+        (input.mapping != Some(target.index), input.size != target.size, input.extension != target.extension,
+            input.path.file_name() != name, (input.size as i64 - target.size as i64).abs())
+    });
+    candidates.truncate(MAX_CANDIDATES);
+    candidates
+}
+
+// try every combination of candidates for the small files of a piece, returns the inputs that make the piece verify
+fn solve_piece(layout:&TorrentLayout, hash:&[u8], piece:usize, inputs:&[SourceFile], targets:&[TargetFile], small:&[usize]) -> Option<Vec<usize>> {
+    let allowed:BTreeSet<usize> = small.iter().cloned().collect();
+    let options:Vec<Vec<usize>> = small.iter().map(|f| candidates(inputs, &targets[*f], &allowed)).collect();
+    if options.iter().any(|o| o.is_empty()) {
+        return None;
+    }
+    let combinations = options.iter().fold(1usize, |c, o| c.saturating_mul(o.len()));
+    if combinations > MAX_COMBINATIONS {
+        println!("  Piece {}: too many combinations to try ({})", piece, combinations);
+        return None;
+    }
+
+    // read every part once, the files that aren't being solved keep their mapping
+    let files = layout.piece_files(piece);
+    let parts:Vec<Vec<Vec<u8>>> = files.iter().map(|f| {
+        let part = layout.file_part(piece, *f);
+        match small.iter().position(|s| s == f) {
+            Some(s) => options[s].iter().map(|i| verify::read_range(inputs, *i, 0, part.start, part.end)).collect(),
+            None => vec![verify::read_range(inputs, targets[*f].mapping.unwrap(), targets[*f].offset, part.start, part.end)],
+        }
+    }).collect();
+
+    (0..combinations).into_par_iter().map(|combination| {
+        // decode the combination into a choice per small file
+        let mut rest = combination;
+        let choice:Vec<usize> = options.iter().map(|o| {
+            let c = rest % o.len();
+            rest /= o.len();
+            c
+        }).collect();
+        let chosen:Vec<usize> = choice.iter().enumerate().map(|(s, c)| options[s][*c]).collect();
+        let distinct:BTreeSet<&usize> = chosen.iter().collect();
+        if distinct.len() != chosen.len() {
+            return None;
+        }
+        let mut hasher = Sha1::new();
+        for (f, file) in files.iter().enumerate() {
+            match small.iter().position(|s| s == file) {
+                Some(s) => hasher.input(&parts[f][choice[s]]),
+                None => hasher.input(&parts[f][0]),
+            }
+        }
+        if hasher.result().as_slice() == hash { Some(chosen) } else { None }
+    }).find_any(|r| r.is_some()).and_then(|r| r)
+}
+
+// the part of a larger file in a piece is known to be right when the piece next to it inside the file verifies
+fn part_verified(layout:&TorrentLayout, table:&PieceTable, piece:usize, file:usize) -> bool {
+    let interior = layout.interior_pieces(file);
+    if interior.start == interior.end {
+        return false;
+    }
+    let neighbour = if piece < interior.start { interior.start } else { interior.end - 1 };
+    table.piece_result(neighbour).is_some_and(|r| r.checked && r.success)
+}
+
+// find the failing pieces with small non-audio files that another choice of inputs could make verify, the parts
+// of the larger files in them have to be known-good already, otherwise no choice for the small files helps
+pub fn small_file_candidates(torrent_meta:&Metainfo, targets:&[TargetFile], table:&PieceTable) -> Vec<usize> {
+    let layout = TorrentLayout::new(torrent_meta);
+    (0..layout.piece_count()).filter(|piece| {
+        if table.piece_result(*piece).is_some_and(|r| r.checked && r.success) {
+            return false;
+        }
+        let files = layout.piece_files(*piece);
+        files.iter().any(|f| is_small(&layout, targets, *f)) && files.iter().all(|f| {
+            is_small(&layout, targets, *f) || (targets[*f].mapping.is_some() && part_verified(&layout, table, *piece, *f))
+        })
+    }).collect()
+}
+
+// solve the failing pieces that contain small non-audio files by trying the plausible inputs for them,
+// a match confirms the mapping of every file in the piece, returns the number of solved pieces
+pub fn solve_small_files(torrent_meta:&Metainfo, inputs:&mut [SourceFile], targets:&mut [TargetFile], table:&mut PieceTable, pieces:&[usize]) -> usize {
+    let layout = TorrentLayout::new(torrent_meta);
+    let hashes:Vec<&[u8]> = torrent_meta.info().pieces().collect();
+    let mut solved = 0;
+    for &piece in pieces {
+        // an earlier solution may have fixed this piece already
+        if table.piece_result(piece).is_some_and(|r| r.checked && r.success) {
+            continue;
+        }
+        let small:Vec<usize> = layout.piece_files(piece).into_iter().filter(|f| is_small(&layout, targets, *f)).collect();
+        let chosen = match solve_piece(&layout, hashes[piece], piece, inputs, targets, &small) {
+            Some(c) => c,
+            None => continue,
+        };
+
+        // move the small targets to the inputs that solved the piece
+        for input in inputs.iter_mut().filter(|i| i.mapping.is_some_and(|m| small.contains(&m))) {
+            input.mapping = None;
+        }
+        for (file, input) in small.iter().zip(chosen.iter()) {
+            inputs[*input].mapping = Some(*file);
+        }
+        let changed = filemapping::assign_mapping(inputs, targets);
+        table.update_files(&changed, inputs, targets);
+        let mapping:Vec<String> = small.iter().zip(chosen.iter()).map(|(f, i)| {
+            format!("{} <= {}", targets[*f].path.to_string_lossy(), inputs[*i].display)
+        }).collect();
+        println!("  Piece {} solved: {}", piece, mapping.join(", "));
+        solved += 1;
+    }
+    solved
+}
@@ -0,0 +1,85 @@
+use ::migration::{SourceFile, TargetFile, add_derived_input};
+use migration::bip_metainfo::{Metainfo};
+use migration::flac;
+use migration::layout::TorrentLayout;
+use migration::overlay::Segment;
+use migration::search;
+use migration::table::PieceTable;
+use migration::variant;
+use std::cmp::min;
+
+// try to reproduce the encoder settings of a FLAC target by decoding the input and re-encoding it with a
+// matrix of settings, on a match the re-encoded file replaces the input for the migration
+pub fn reencode_search(index:usize, torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], table:&mut PieceTable) -> bool {
+    let source = match targets[index].mapping {
+        Some(m) => m,
+        None => return false,
+    };
+    println!("Re-encoding {}", inputs[source].display);
+    let decoded = match flac::decode(&inputs[source]) {
+        Ok(d) => d,
+        Err(e) => {
+            println!("  Unable to decode input: {}", e);
+            return false;
+        }
+    };
+    let layout = TorrentLayout::new(torrent_meta);
+    let mut window = match search::middle_window(&layout, index, inputs[source].size) {
+        Some(w) => w,
+        None => {
+            println!("  The file has no interior piece to test encoder settings with");
+            return false;
+        }
+    };
+    let hash = torrent_meta.info().pieces().nth(window.piece).unwrap();
+
+    // the audio of the piece sits at about the same share of the audio in the input, with a margin for the
+    // difference in compression between the encoders like the window of the middle piece search
+    let header = decoded.header.len() as u64;
+    let scale = inputs[source].size.saturating_sub(header) as f64 / targets[index].size.saturating_sub(header).max(1) as f64;
+    let start = header as f64 + (window.normal as f64 - header as f64).max(0.0) * scale;
+    let end = start + window.length as f64 * scale;
+    let margin = (inputs[source].size as f64 - targets[index].size as f64).abs() + 2.0 * window.length as f64;
+    let first_sample = decoded.sample_at((start - margin).max(0.0) as u64);
+    let last_sample = decoded.sample_at((end + margin) as u64 + 1);
+
+    for settings in flac::settings_matrix() {
+        // encode only the frames around the piece and look for it in them
+        let count = flac::frame_count(&decoded, &settings);
+        let block_size = settings.block_size as u64;
+        let frames = (first_sample / block_size) as usize..min((last_sample / block_size) as usize + 1, count);
+        let part = flac::encode_frames(&decoded, &settings, frames.clone()).concat();
+        window.start = 0;
+        window.end = part.len() as u64 + 1;
+        let position = match search::search_buffer(&part, hash, &window) {
+            Some(p) => p,
+            None => continue,
+        };
+        println!("  Audio matches with encoder settings: {}", settings);
+
+        // encode the whole file once with the settings that matched
+        let all = flac::encode_frames(&decoded, &settings, 0..count);
+        let before:usize = all[..frames.start].iter().map(|f| f.len()).sum();
+        let encoded = flac::assemble(&decoded, &settings, &all);
+        drop(all);
+        if let Err(e) = add_derived_input(inputs, targets, source, &encoded, "re-encoded") {
+            println!("  Unable to write temporary file (re-encoded): {}", e);
+            return false;
+        }
+        targets[index].offset = window.offset(header + before as u64 + position);
+        table.update_file(index, inputs, targets);
+
+        // the audio frames match, the header with the vendor string of another encoder release may verify better
+        let size = encoded.len() as u64;
+        let variants = flac::VENDORS.iter().filter_map(|vendor| {
+            flac::with_vendor(&encoded[..header as usize], vendor)
+                .map(|h| (format!("re-encoded, {}", vendor), vec![Segment::Data(h), Segment::File(header..size)]))
+        });
+        if table.file_complete(index) || !variant::use_best_variant(index, torrent_meta, inputs, targets, table, variants, true) {
+            println!("  Using re-encoded");
+        }
+        return true;
+    }
+    println!("  No matching encoder settings found");
+    false
+}
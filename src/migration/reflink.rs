@@ -0,0 +1,104 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+#[cfg(target_os = "linux")]
+mod ioctl {
+    use migration::libc;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    // from linux/fs.h
+    const FICLONE:libc::c_ulong = 0x4004_9409;
+    const FICLONERANGE:libc::c_ulong = 0x4020_940d;
+
+    #[repr(C)]
+    struct FileCloneRange {
+        src_fd: i64,
+        src_offset: u64,
+        src_length: u64,
+        dest_offset: u64,
+    }
+
+    pub fn clone_file(source:&File, destination:&File) -> io::Result<()> {
+        let result = unsafe { libc::ioctl(destination.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+        if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+
+    pub fn clone_range(source:&File, source_offset:u64, destination:&File, destination_offset:u64, length:u64) -> io::Result<()> {
+        let range = FileCloneRange { src_fd:source.as_raw_fd() as i64, src_offset:source_offset, src_length:length, dest_offset:destination_offset };
+        let result = unsafe { libc::ioctl(destination.as_raw_fd(), FICLONERANGE as _, &range) };
+        if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod ioctl {
+    use std::fs::File;
+    use std::io;
+
+    pub fn clone_file(_source:&File, _destination:&File) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported on this platform"))
+    }
+
+    pub fn clone_range(_source:&File, _source_offset:u64, _destination:&File, _destination_offset:u64, _length:u64) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "reflinks are not supported on this platform"))
+    }
+}
+
+pub use self::ioctl::{clone_file, clone_range};
+
+// the first existing directory on the path, the output directory is only created when migrating
+pub fn existing_ancestor(path:&Path) -> Option<&Path> {
+    path.ancestors().find(|p| p.is_dir())
+}
+
+#[cfg(unix)]
+fn device_and_block_size(path:&Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = path.metadata().ok()?;
+    Some((metadata.dev(), metadata.blksize()))
+}
+
+#[cfg(not(unix))]
+fn device_and_block_size(_path:&Path) -> Option<(u64, u64)> {
+    None
+}
+
+// whether a file and the output are on the same filesystem, hardlinks can't cross filesystems
+pub fn same_device(input:&Path, output:&Path) -> bool {
+    let directory = match existing_ancestor(output) {
+        Some(d) => d,
+        None => return false,
+    };
+    match (device_and_block_size(input), device_and_block_size(directory)) {
+        (Some((input_device, _)), Some((output_device, _))) => input_device == output_device,
+        _ => false,
+    }
+}
+
+// check if files can be cloned from the input into the output, this needs both on the same filesystem
+// and a filesystem that supports reflinks, returns the block size extents are shared in
+pub fn detect(input:&Path, output:&Path) -> Option<u64> {
+    let directory = existing_ancestor(output)?;
+    let (input_device, _) = device_and_block_size(input)?;
+    let (output_device, block_size) = device_and_block_size(directory)?;
+    if input_device != output_device || block_size == 0 {
+        return None;
+    }
+    // clone a single block between two scratch files to find out if the filesystem supports it
+    let source_path = directory.join(format!(".mtmigrate-reflink-{}-a", process::id()));
+    let clone_path = directory.join(format!(".mtmigrate-reflink-{}-b", process::id()));
+    let result = File::create(&source_path).and_then(|mut source| {
+        source.write_all(&vec![0u8; block_size as usize])?;
+        source.sync_all()?;
+        let clone = File::create(&clone_path)?;
+        clone_file(&source, &clone)
+    });
+    fs::remove_file(&source_path).ok();
+    fs::remove_file(&clone_path).ok();
+    result.ok().map(|_| block_size)
+}
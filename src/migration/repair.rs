@@ -0,0 +1,221 @@
+use ::migration::{SourceFile, TargetFile, add_overlay_input};
+use migration::bip_metainfo::{Metainfo};
+use migration::layout::TorrentLayout;
+use migration::overlay;
+use migration::table::PieceTable;
+use migration::sha1::{Sha1, Digest};
+use migration::rayon::prelude::*;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// how long a single piece may be worked on before giving up
+const REPAIR_TIME_LIMIT:u64 = 120;
+
+// the kinds of corruption to try, substitutions skip the values already covered by the bit flips
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Corruption {
+    BitFlip,
+    ByteSubstitution,
+}
+
+// a failing piece that lies fully inside one input, with the pieces on both sides of it passing
+#[derive(Debug, Clone)]
+pub struct RepairCandidate {
+    pub piece: usize,
+    pub file: usize,
+    pub input: usize,
+    pub position: u64, // where the piece starts in the input
+}
+
+#[derive(Debug, PartialEq)]
+enum RepairResult {
+    Repaired(usize, u8),
+    NotFound,
+    TimedOut,
+}
+
+// find the failing pieces that are worth a repair attempt, when the neighbours pass at the same
+// offset the alignment is right and a failure is most likely caused by a small corruption
+pub fn repair_candidates(torrent_meta:&Metainfo, inputs:&[SourceFile], targets:&[TargetFile], table:&PieceTable) -> Vec<RepairCandidate> {
+    let layout = TorrentLayout::new(torrent_meta);
+    let mut candidates = Vec::new();
+    for (file, target) in targets.iter().enumerate() {
+        let input = match target.mapping {
+            Some(m) => m,
+            None => continue,
+        };
+        let pieces = layout.file_pieces(file);
+        for piece in layout.interior_pieces(file) {
+            if table.piece_result(piece).is_none_or(|r| !r.checked || r.success) {
+                continue;
+            }
+            // the first and last pieces of a file hold the tags, a failure there is usually a real difference
+            if piece == pieces.start || piece + 1 == pieces.end {
+                continue;
+            }
+            if ![piece - 1, piece + 1].iter().all(|n| table.piece_result(*n).is_some_and(|r| r.checked && r.success)) {
+                continue;
+            }
+            // padding can't be corrupted, only pieces made up of input data qualify
+            let position = layout.piece_position(piece, file) + target.offset;
+            if position < 0 || position as u64 + layout.piece_size(piece) > inputs[input].size {
+                continue;
+            }
+            candidates.push(RepairCandidate { piece, file, input, position:position as u64 });
+        }
+    }
+    candidates
+}
+
+// the values to try for a byte
+fn variants(original:u8, corruption:Corruption) -> Vec<u8> {
+    match corruption {
+        Corruption::BitFlip => (0..8).map(|bit| original ^ (1 << bit)).collect(),
+        Corruption::ByteSubstitution => (0..256u32).map(|v| v as u8).filter(|v| (v ^ original).count_ones() > 1).collect(),
+    }
+}
+
+// look for a single changed byte that makes the data hash to the expected value, the hash state before
+// every 64 byte block is kept, so a change only has to be hashed from its own block on
+fn find_repair(data:&[u8], hash:&[u8], corruption:Corruption, deadline:Instant) -> RepairResult {
+    let mut states = Vec::with_capacity(data.len() / 64 + 1);
+    let mut state = Sha1::new();
+    for block in data.chunks(64) {
+        states.push(state.clone());
+        state.input(block);
+    }
+    let timed_out = AtomicBool::new(false);
+    let found = (0..states.len()).into_par_iter().map(|b| {
+        let start = b * 64;
+        let end = min(start + 64, data.len());
+        let mut block = data[start..end].to_vec();
+        for i in 0..block.len() {
+            if timed_out.load(Ordering::Relaxed) {
+                return None;
+            }
+            if Instant::now() > deadline {
+                timed_out.store(true, Ordering::Relaxed);
+                return None;
+            }
+            let original = block[i];
+            for value in variants(original, corruption) {
+                block[i] = value;
+                let mut hasher = states[b].clone();
+                hasher.input(&block);
+                hasher.input(&data[end..]);
+                if hasher.result().as_slice() == hash {
+                    return Some((start + i, value));
+                }
+            }
+            block[i] = original;
+        }
+        None
+    }).find_any(|r| r.is_some()).and_then(|r| r);
+    match found {
+        Some((position, value)) => RepairResult::Repaired(position, value),
+        None if timed_out.load(Ordering::Relaxed) => RepairResult::TimedOut,
+        None => RepairResult::NotFound,
+    }
+}
+
+// try to repair the candidate pieces, the repaired inputs replace the originals for the migration
+// returns the number of repaired pieces
+pub fn repair_pieces(torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], table:&mut PieceTable, candidates:&[RepairCandidate], corruption:Corruption) -> usize {
+    let layout = TorrentLayout::new(torrent_meta);
+    let hashes:Vec<&[u8]> = torrent_meta.info().pieces().collect();
+    let mut repairs:BTreeMap<usize, Vec<(u64, u8)>> = BTreeMap::new();
+    for candidate in candidates {
+        let length = layout.piece_size(candidate.piece);
+        let mut data = Vec::with_capacity(length as usize);
+        let mut file = overlay::open(&inputs[candidate.input]).expect("Unable to open input file");
+        file.seek(SeekFrom::Start(candidate.position)).expect("Unable to seek in file");
+        file.take(length).read_to_end(&mut data).expect("Unable to read file");
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_secs(REPAIR_TIME_LIMIT);
+        let name = targets[candidate.file].path.to_string_lossy().into_owned();
+        match find_repair(&data, hashes[candidate.piece], corruption, deadline) {
+            RepairResult::Repaired(position, value) => {
+                println!("  {} piece {}: byte {} of the input changed from {:#04x} to {:#04x}", name, candidate.piece,
+                    candidate.position + position as u64, data[position], value);
+                repairs.entry(candidate.file).or_default().push((candidate.position + position as u64, value));
+            },
+            RepairResult::NotFound => {
+                println!("  {} piece {}: no repair found", name, candidate.piece);
+            },
+            RepairResult::TimedOut => {
+                println!("  {} piece {}: gave up after {} seconds", name, candidate.piece, started.elapsed().as_secs());
+            },
+        }
+    }
+
+    // the repaired bytes are laid over every input with repairs, the input itself isn't copied or changed
+    let mut repaired = 0;
+    for (file, mut changes) in repairs {
+        let source = match targets[file].mapping {
+            Some(m) => m,
+            None => continue,
+        };
+        changes.sort();
+        repaired += changes.len();
+        let segments = overlay::patch(inputs[source].size, changes.into_iter().map(|(position, value)| (position, vec![value])).collect());
+        add_overlay_input(inputs, targets, source, segments, "repaired");
+        table.update_file(file, inputs, targets);
+    }
+    repaired
+}
+
+#[cfg(test)]
+mod tests {
+    use migration::sha1::{Sha1, Digest};
+    use super::{Corruption, RepairResult, find_repair, variants};
+    use std::time::{Duration, Instant};
+
+    // a piece of a few blocks, with data that doesn't repeat
+    fn piece() -> (Vec<u8>, Vec<u8>) {
+        let data:Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let hash = Sha1::digest(&data).to_vec();
+        (data, hash)
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    #[test]
+    fn byte_variants() {
+        assert_eq!(variants(0x10, Corruption::BitFlip), vec![0x11, 0x12, 0x14, 0x18, 0x00, 0x30, 0x50, 0x90]);
+        let substitutions = variants(0x10, Corruption::ByteSubstitution);
+        assert_eq!(substitutions.len(), 256 - 1 - 8);
+        assert!(!substitutions.contains(&0x10) && !substitutions.contains(&0x11));
+    }
+
+    #[test]
+    fn repair_single_bit() {
+        let (mut data, hash) = piece();
+        let original = data[700];
+        data[700] ^= 0x20;
+        assert_eq!(find_repair(&data, &hash, Corruption::BitFlip, deadline()), RepairResult::Repaired(700, original));
+    }
+
+    #[test]
+    fn repair_substituted_byte() {
+        let (mut data, hash) = piece();
+        let original = data[64];
+        data[64] = !original;
+        assert_eq!(find_repair(&data, &hash, Corruption::BitFlip, deadline()), RepairResult::NotFound);
+        assert_eq!(find_repair(&data, &hash, Corruption::ByteSubstitution, deadline()), RepairResult::Repaired(64, original));
+    }
+
+    #[test]
+    fn repair_gives_up() {
+        let (mut data, hash) = piece();
+        data[3] ^= 0x01;
+        data[900] ^= 0x01;
+        assert_eq!(find_repair(&data, &hash, Corruption::BitFlip, deadline()), RepairResult::NotFound);
+        assert_eq!(find_repair(&data, &hash, Corruption::BitFlip, Instant::now() - Duration::from_secs(1)), RepairResult::TimedOut);
+    }
+}
@@ -0,0 +1,79 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::bip_metainfo::{Metainfo};
+use migration::cache::PieceCache;
+use migration::migrator::Plan;
+use migration::table::PieceTable;
+use std::path::{Path, PathBuf};
+
+// the files an earlier migration of the same torrent left behind, in the output directory or in the partial
+// output of an interrupted run, the partial output takes precedence as it is the most recent
+pub fn existing_files(torrent_meta:&Metainfo, targets:&[TargetFile], output:&Path, staged:Option<&Path>) -> Vec<Option<PathBuf>> {
+    let directory = torrent_meta.info().directory().map_or(PathBuf::new(), |d| d.to_path_buf());
+    targets.iter().map(|target| {
+        let mut roots = Vec::new();
+        if let Some(s) = staged {
+            roots.push(s.join(&directory));
+        }
+        roots.push(output.join(&directory));
+        roots.into_iter().map(|r| r.join(&target.path)).find(|p| p.is_file())
+    }).collect()
+}
+
+// verify the existing files of the right size piece by piece, pieces shared with a file that is missing or
+// has the wrong size are checked with the data the migration would write for it
+// only the pieces of those files are checked, the results of the other pieces stay with the table of the matcher
+// returns which target files are complete and can be kept
+pub fn check_existing(torrent_meta:&Metainfo, piece_cache:&PieceCache, inputs:&[SourceFile], targets:&[TargetFile], existing:&[Option<PathBuf>]) -> Vec<bool> {
+    let mut check_inputs = inputs.to_vec();
+    let mut check_targets = targets.to_vec();
+    let mut candidates = Vec::new();
+    for (target, path) in check_targets.iter_mut().zip(existing.iter()) {
+        let path = match *path {
+            Some(ref p) => p,
+            None => continue,
+        };
+        let size = match path.metadata() {
+            Ok(m) => m.len(),
+            Err(_) => continue,
+        };
+        if size != target.size {
+            continue;
+        }
+        target.mapping = Some(check_inputs.len());
+        target.offset = 0;
+        check_inputs.push(SourceFile {
+            path: path.clone(),
+            display: target.path.to_string_lossy().into_owned(),
+            extension: target.extension.clone(),
+            is_audio: target.is_audio,
+            size,
+            mapping: Some(target.index),
+            derived: false,
+            segments: None,
+            original: None,
+            verified: None,
+        });
+        candidates.push(target.index);
+    }
+    if candidates.is_empty() {
+        return vec![false; targets.len()];
+    }
+    let mut table = PieceTable::new(torrent_meta, piece_cache);
+    table.update_files(&candidates, &check_inputs, &check_targets);
+    targets.iter().map(|t| candidates.contains(&t.index) && table.file_complete(t.index)).collect()
+}
+
+// show which files are resumed from the earlier migration and how the others are written
+pub fn print_resume(targets:&[TargetFile], existing:&[Option<PathBuf>], kept:&[bool], plan:&Plan) {
+    println!("Resuming an earlier migration of this torrent:");
+    let max = targets.iter().map(|e| e.path.to_string_lossy().len()).max().unwrap_or(0);
+    for target in targets {
+        let state = match existing[target.index] {
+            _ if kept[target.index] => "complete",
+            None => "missing",
+            Some(ref p) if p.metadata().map(|m| m.len()).unwrap_or(0) != target.size => "wrong size",
+            Some(_) => "failing pieces",
+        };
+        println!("  {:width$} {}, {}", target.path.to_string_lossy(), state, plan.describe(target.index), width = max);
+    }
+}
@@ -0,0 +1,217 @@
+use ::migration::SourceFile;
+use migration::overlay;
+use migration::overlay::Segment;
+use std::cmp::min;
+use std::io::{Read, Seek, SeekFrom};
+
+// layouts tested per file, every layout is checked piece by piece
+const MAX_LAYOUTS:usize = 16;
+// chunk groups up to this size are also tried in every other order
+const MAX_REORDERED:usize = 4;
+
+// the container formats, WAV uses little endian sizes and AIFF big endian
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Wave,
+    Aiff,
+}
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    id: [u8; 4],
+    start: u64,
+    end: u64, // including the pad byte
+}
+
+impl Chunk {
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&self.id).trim().to_string()
+    }
+
+    fn length(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+// where a chunk goes in a layout
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placement {
+    Before,
+    After,
+    Omitted,
+}
+
+fn read_size(bytes:&[u8], format:Format) -> u64 {
+    match format {
+        Format::Wave => bytes.iter().rev().fold(0u64, |v, b| (v << 8) | *b as u64),
+        Format::Aiff => bytes.iter().fold(0u64, |v, b| (v << 8) | *b as u64),
+    }
+}
+
+fn write_size(bytes:&mut [u8], size:u64, format:Format) {
+    for (i, byte) in bytes[..4].iter_mut().enumerate() {
+        let shift = match format {
+            Format::Wave => 8 * i,
+            Format::Aiff => 8 * (3 - i),
+        };
+        *byte = (size >> shift) as u8;
+    }
+}
+
+// parse the chunks of a WAV or AIFF file, only the chunk headers are read
+fn parse(input:&SourceFile) -> Option<(Format, [u8; 12], Vec<Chunk>, u64)> {
+    let mut file = overlay::open(input).ok()?;
+    let size = input.size;
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header).ok()?;
+    let format = match (&header[0..4], &header[8..12]) {
+        (b"RIFF", b"WAVE") => Format::Wave,
+        (b"FORM", b"AIFF") | (b"FORM", b"AIFC") => Format::Aiff,
+        _ => return None,
+    };
+    let mut chunks = Vec::new();
+    let mut position = 12;
+    while position + 8 <= size {
+        let mut chunk_header = [0u8; 8];
+        file.seek(SeekFrom::Start(position)).ok()?;
+        file.read_exact(&mut chunk_header).ok()?;
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&chunk_header[0..4]);
+        let length = read_size(&chunk_header[4..8], format);
+        // chunks are padded to an even size, the last pad byte is sometimes missing
+        let end = position + 8 + length + (length & 1);
+        if position + 8 + length > size {
+            return None;
+        }
+        chunks.push(Chunk { id, start:position, end:min(end, size) });
+        position = end;
+    }
+    Some((format, header, chunks, size))
+}
+
+fn is_audio(chunk:&Chunk) -> bool {
+    &chunk.id == b"data" || &chunk.id == b"SSND"
+}
+
+// the chunks that describe the audio always stay in front of it
+fn is_essential(chunk:&Chunk) -> bool {
+    &chunk.id == b"fmt " || &chunk.id == b"COMM"
+}
+
+// every order of a group of chunks starting with the current one, larger groups keep their order
+fn orders(group:&[usize]) -> Vec<Vec<usize>> {
+    if group.len() > MAX_REORDERED {
+        return vec![group.to_vec()];
+    }
+    permutations(group)
+}
+
+fn permutations(group:&[usize]) -> Vec<Vec<usize>> {
+    if group.len() < 2 {
+        return vec![group.to_vec()];
+    }
+    let mut permutations = Vec::new();
+    for (i, first) in group.iter().enumerate() {
+        let mut rest = group.to_vec();
+        rest.remove(i);
+        for mut permutation in self::permutations(&rest) {
+            permutation.insert(0, *first);
+            permutations.push(permutation);
+        }
+    }
+    permutations
+}
+
+// describe a layout as the chunks of the input in the given order behind a header with the new container size
+fn build(header:&[u8; 12], format:Format, chunks:&[Chunk], order:&[usize]) -> Vec<Segment> {
+    let size = 4 + order.iter().map(|c| chunks[*c].length()).sum::<u64>();
+    let mut header = header.to_vec();
+    write_size(&mut header[4..8], size, format);
+    let mut segments = vec![Segment::Data(header)];
+    for chunk in order.iter().map(|c| &chunks[*c]) {
+        // neighbouring chunks that stay together are read as one range
+        if let Some(&mut Segment::File(ref mut range)) = segments.last_mut() {
+            if range.end == chunk.start {
+                range.end = chunk.end;
+                continue;
+            }
+        }
+        segments.push(Segment::File(chunk.start..chunk.end));
+    }
+    segments
+}
+
+// alternative layouts of a WAV or AIFF file, with the non-audio chunks moved in front of or behind the audio
+// chunk, reordered or left out, a layout only describes which ranges of the input make up the file
+// when the position of the audio in the target is known only layouts that put it there are kept,
+// layouts matching the target size come first
+pub fn layouts(input:&SourceFile, target_size:u64, target_audio_start:Option<i64>) -> Vec<(String, Vec<Segment>)> {
+    let (format, header, chunks, size) = match parse(input) {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+    let audio = match chunks.iter().position(is_audio) {
+        Some(a) => a,
+        None => return Vec::new(),
+    };
+    let optional:Vec<usize> = (0..chunks.len()).filter(|c| *c != audio && !is_essential(&chunks[*c])).collect();
+    if optional.len() > 8 {
+        return Vec::new();
+    }
+
+    // every optional chunk goes before, after or nowhere, the position of the audio only depends on
+    // which chunks go in front of it so the orders are only worked out for the placements that are kept
+    let mut hypotheses = Vec::new();
+    for combination in 0..3usize.pow(optional.len() as u32) {
+        let mut placements:Vec<Placement> = chunks.iter().map(|_| Placement::Before).collect();
+        let mut rest = combination;
+        for c in &optional {
+            placements[*c] = match rest % 3 {
+                0 => Placement::Before,
+                1 => Placement::After,
+                _ => Placement::Omitted,
+            };
+            rest /= 3;
+        }
+        let before:Vec<usize> = (0..chunks.len()).filter(|c| *c != audio && placements[*c] == Placement::Before).collect();
+        let after:Vec<usize> = (0..chunks.len()).filter(|c| placements[*c] == Placement::After).collect();
+        let audio_start = 12 + before.iter().map(|c| chunks[*c].length()).sum::<u64>();
+        let layout_size = audio_start + chunks[audio].length() + after.iter().map(|c| chunks[*c].length()).sum::<u64>();
+        if target_audio_start.is_some() && target_audio_start != Some(audio_start as i64) {
+            continue;
+        }
+        hypotheses.push((layout_size != target_size, before, after));
+    }
+
+    // layouts matching the target size first, within those the chunks in their current order first
+    let original = vec![Segment::Data(header.to_vec()), Segment::File(12..size)];
+    let mut layouts:Vec<(String, Vec<Segment>)> = Vec::new();
+    for &(mismatch, reordered) in &[(false, false), (false, true), (true, false), (true, true)] {
+        for (_, before, after) in hypotheses.iter().filter(|h| h.0 == mismatch) {
+            let (befores, afters) = if reordered { (orders(before), orders(after)) } else { (vec![before.clone()], vec![after.clone()]) };
+            for before in &befores {
+                for after in &afters {
+                    if layouts.len() == MAX_LAYOUTS {
+                        return layouts;
+                    }
+                    let mut order = before.clone();
+                    order.push(audio);
+                    order.extend(after.iter().cloned());
+                    let layout = build(&header, format, &chunks, &order);
+                    if layout == original || layouts.iter().any(|l| l.1 == layout) {
+                        continue;
+                    }
+                    let names:Vec<String> = order.iter().map(|c| chunks[*c].name()).collect();
+                    layouts.push((format!("chunks {}", names.join(", ")), layout));
+                }
+            }
+        }
+    }
+    layouts
+}
+
+// where the audio chunk starts in the file
+pub fn audio_start(input:&SourceFile) -> Option<u64> {
+    let (_, _, chunks, _) = parse(input)?;
+    chunks.iter().find(|c| is_audio(c)).map(|c| c.start)
+}
@@ -0,0 +1,118 @@
+use ::migration::SourceFile;
+use migration::layout::TorrentLayout;
+use migration::overlay;
+use migration::sha1::{Sha1, Digest};
+use migration::rayon;
+use migration::rayon::prelude::*;
+use std::cmp::{min, max};
+use std::io::{Read, Seek, SeekFrom};
+
+// a piece of a target to look for in an input, positions are byte offsets in the input
+#[derive(Debug, Clone)]
+pub struct SearchWindow {
+    pub piece: usize,
+    pub normal: i64, // where the piece starts in the target file
+    pub start: u64,
+    pub end: u64, // exclusive, the last position a piece can start at plus one
+    pub length: usize,
+}
+
+impl SearchWindow {
+    // a window of positions around where the piece would be without any offset, clamped to the input
+    pub fn around(layout:&TorrentLayout, file:usize, piece:usize, windowsize:i64, input_size:u64) -> Option<SearchWindow> {
+        let length = layout.piece_size(piece) as usize;
+        let normal = layout.piece_position(piece, file);
+        let start = max(normal - windowsize, 0);
+        let end = min(normal + windowsize, input_size as i64 - length as i64 + 1);
+        if end <= start {
+            return None;
+        }
+        Some(SearchWindow { piece, normal, start:start as u64, end:end as u64, length })
+    }
+
+    // a single position, for testing a known offset
+    pub fn at(layout:&TorrentLayout, file:usize, piece:usize, offset:i64, input_size:u64) -> Option<SearchWindow> {
+        let length = layout.piece_size(piece) as usize;
+        let normal = layout.piece_position(piece, file);
+        let position = normal + offset;
+        if position < 0 || position + length as i64 > input_size as i64 {
+            return None;
+        }
+        Some(SearchWindow { piece, normal, start:position as u64, end:position as u64 + 1, length })
+    }
+
+    // the offset to apply to the target if the piece was found at the given input position
+    pub fn offset(&self, position:u64) -> i64 {
+        position as i64 - self.normal
+    }
+}
+
+// the window around the middle interior piece of a file, a file without interior pieces can't be searched in its own input
+pub fn middle_window(layout:&TorrentLayout, file:usize, input_size:u64) -> Option<SearchWindow> {
+    let interior = layout.interior_pieces(file);
+    if interior.start == interior.end {
+        return None;
+    }
+    let piece = interior.start + (interior.end - interior.start) / 2;
+    let windowsize = (layout.file_size(file) as i64 - input_size as i64).abs() + layout.piece_size(piece) as i64;
+    SearchWindow::around(layout, file, piece, windowsize, input_size)
+}
+
+// search a window in data that is already in memory
+pub fn search_buffer(buffer:&[u8], hash:&[u8], window:&SearchWindow) -> Option<u64> {
+    let end = min(window.end, (buffer.len() as u64 + 1).saturating_sub(window.length as u64));
+    if end <= window.start {
+        return None;
+    }
+    (window.start..end).into_par_iter().find_any(|x| {
+        Sha1::digest(&buffer[*x as usize..*x as usize+window.length]).as_slice() == hash
+    })
+}
+
+// positions scanned per chunk at most in piece lengths, every thread holds one chunk and a piece length of overlap
+// so a search takes a few piece lengths of memory per thread
+const CHUNK_PIECES:u64 = 4;
+
+// search a window in a file, the window is split over the threads in chunks that each read their own part of
+// the file with a piece length of overlap, so memory use stays bounded regardless of the file size
+pub fn search_file(input:&SourceFile, hash:&[u8], window:&SearchWindow) -> Option<u64> {
+    let span = window.end - window.start;
+    let threads = rayon::current_num_threads() as u64;
+    let chunk = max(min(span.div_ceil(threads), CHUNK_PIECES * window.length as u64), 1);
+    let chunks = span.div_ceil(chunk);
+    (0..chunks).into_par_iter().map(|c| {
+        let first = window.start + c * chunk;
+        let last = min(first + chunk, window.end);
+        let mut buffer = Vec::with_capacity((last - first) as usize + window.length - 1);
+        let mut file = overlay::open(input).expect("Unable to open file for reading");
+        file.seek(SeekFrom::Start(first)).expect("Unable to seek in file");
+        file.take(last - first + window.length as u64 - 1).read_to_end(&mut buffer).expect("Unable to read file");
+        let positions = min((last - first) as usize, (buffer.len() + 1).saturating_sub(window.length));
+        (0..positions).find(|x| Sha1::digest(&buffer[*x..*x+window.length]).as_slice() == hash).map(|x| first + x as u64)
+    }).find_any(|r| r.is_some()).and_then(|r| r)
+}
+
+// search a set of sorted positions in a file, nearby positions are read together in chunks that are as large as
+// the chunks of all threads in a window search together, and the positions in a chunk are hashed in parallel
+pub fn search_positions(input:&SourceFile, hash:&[u8], positions:&[u64], length:usize) -> Option<u64> {
+    let span = rayon::current_num_threads() as u64 * CHUNK_PIECES * length as u64;
+    let mut file = overlay::open(input).expect("Unable to open file for reading");
+    let mut rest = positions;
+    while let Some(&first) = rest.first() {
+        let count = rest.iter().position(|p| *p - first > span).unwrap_or(rest.len());
+        let (group, next) = rest.split_at(count);
+        rest = next;
+        let last = *group.last().unwrap();
+        let mut buffer = Vec::with_capacity((last - first) as usize + length);
+        file.seek(SeekFrom::Start(first)).expect("Unable to seek in file");
+        (&mut file).take(last - first + length as u64).read_to_end(&mut buffer).expect("Unable to read file");
+        let found = group.par_iter().cloned().filter(|p| (*p - first) as usize + length <= buffer.len()).find_any(|p| {
+            let start = (*p - first) as usize;
+            Sha1::digest(&buffer[start..start+length]).as_slice() == hash
+        });
+        if found.is_some() {
+            return found;
+        }
+    }
+    None
+}
@@ -0,0 +1,78 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::bip_metainfo::{Metainfo};
+use migration::cache::PieceCache;
+use migration::table::PieceTable;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// verify the inputs against the torrent they were originally downloaded with, inputs are matched to its
+// files by path and otherwise by size, which also identifies inputs that were renamed since
+// returns false when inputs are corrupted and the user doesn't want to continue
+pub fn check_source(source_meta:&Metainfo, source_targets:&mut [TargetFile], inputs:&mut [SourceFile], cache_dir:Option<PathBuf>) -> bool {
+    // match by path first
+    for target in source_targets.iter_mut() {
+        target.mapping = inputs.iter().position(|i| Path::new(&i.display) == target.path);
+    }
+    // then by size and extension, only when that is unambiguous
+    let mut renamed = Vec::new();
+    for index in 0..source_targets.len() {
+        if source_targets[index].mapping.is_some() {
+            continue;
+        }
+        let candidates:Vec<usize> = (0..inputs.len()).filter(|i| {
+            inputs[*i].size == source_targets[index].size && inputs[*i].extension == source_targets[index].extension &&
+                !source_targets.iter().any(|t| t.mapping == Some(*i))
+        }).collect();
+        if candidates.len() == 1 {
+            source_targets[index].mapping = Some(candidates[0]);
+            renamed.push(index);
+        }
+    }
+
+    // hash check the source torrent, its results are cached like those of the target torrent
+    let piece_cache = PieceCache::load(cache_dir, source_meta);
+    let corrupted = {
+        let mut table = PieceTable::new(source_meta, &piece_cache);
+        table.check_all(inputs, source_targets);
+        println!("Source torrent check:");
+        let max = source_targets.iter().map(|e| e.path.to_string_lossy().len()).max().unwrap_or(0);
+        let mut corrupted = 0;
+        for (index, target) in source_targets.iter().enumerate() {
+            let info = table.file_result(index, source_targets);
+            let failed = (info.total - info.good) + (info.boundary_total - info.boundary_good);
+            let state = match target.mapping {
+                None => "missing from input".to_string(),
+                Some(_) if failed > 0 => format!("corrupted, {} of {} pieces failed", failed, info.total + info.boundary_total),
+                Some(_) if info.blocked > 0 => format!("can't be fully checked, {} pieces shared with a missing file", info.blocked),
+                Some(_) => "bit-perfect".to_string(),
+            };
+            let name = match target.mapping {
+                Some(m) if renamed.contains(&index) => format!(" (renamed to {})", inputs[m].display),
+                _ => String::new(),
+            };
+            println!("  {:3$} = {}{}", target.path.to_string_lossy(), state, name, max+1);
+            if let Some(m) = target.mapping {
+                inputs[m].original = Some(target.path.clone());
+                // pieces blocked by a missing neighbour leave the input unverified, not corrupted
+                inputs[m].verified = match (failed, info.blocked) {
+                    (0, 0) => Some(true),
+                    (0, _) => None,
+                    _ => Some(false),
+                };
+                if failed > 0 {
+                    corrupted += 1;
+                }
+            }
+        }
+        corrupted
+    };
+    piece_cache.save();
+
+    if corrupted == 0 {
+        return true;
+    }
+    println!("{} inputs are corrupted compared to the source torrent, migrate them anyway? (y/n) [n]", corrupted);
+    let mut reply = String::new();
+    io::stdin().read_line(&mut reply).unwrap();
+    matches!(reply.trim(), "y" | "yes")
+}
@@ -0,0 +1,46 @@
+use migration::reflink;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// the space available to the user on the filesystem a path will be created on: (bytes, fragment size)
+#[cfg(unix)]
+pub fn available(path:&Path) -> Option<(u64, u64)> {
+    use migration::libc;
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    let existing = reflink::existing_ancestor(path).unwrap_or_else(|| Path::new("."));
+    let name = CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat:libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(name.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fragment = stat.f_frsize as u64;
+    Some((stat.f_bavail as u64 * fragment, fragment))
+}
+
+#[cfg(not(unix))]
+pub fn available(_path:&Path) -> Option<(u64, u64)> {
+    None
+}
+
+// allocate every block of a file up to its size, the data that is already there is kept
+#[cfg(target_os = "linux")]
+pub fn preallocate(file:&File, size:u64) -> io::Result<()> {
+    use migration::libc;
+    use std::os::unix::io::AsRawFd;
+    if size == 0 {
+        return Ok(());
+    }
+    // posix_fallocate returns the error instead of setting errno
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, size as libc::off_t) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file:&File, _size:u64) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "preallocation is only supported on Linux"))
+}
@@ -0,0 +1,192 @@
+use migration::ctrlc;
+use migration::walkdir::WalkDir;
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STAGING_PREFIX:&str = ".mtmigrate-staging-";
+
+// set while the output is written, an interruption then stops the writing instead of the process
+static WRITING:AtomicBool = AtomicBool::new(false);
+static INTERRUPTED:AtomicBool = AtomicBool::new(false);
+
+// the temporary files of derived inputs, removed when the process is interrupted or panics
+static TEMPORARY:Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// the payload the writing is unwound with when it was interrupted
+pub struct Interrupted;
+
+// stop at Ctrl-C, while the output is written it is stopped at the next chunk so the staging directory can be removed
+pub fn install_handler() {
+    ctrlc::set_handler(|| {
+        if WRITING.load(Ordering::SeqCst) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        } else {
+            remove_temporary_files();
+            process::exit(130);
+        }
+    }).expect("Unable to install the interrupt handler");
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        remove_temporary_files();
+        default(info);
+    }));
+}
+
+pub fn add_temporary_file(path:&Path) {
+    TEMPORARY.lock().unwrap().push(path.to_path_buf());
+}
+
+// remove a temporary file that is no longer needed
+pub fn remove_temporary_file(path:&Path) {
+    TEMPORARY.lock().unwrap().retain(|p| p != path);
+    fs::remove_file(path).ok();
+}
+
+// stop removing the temporary files at an interruption, an interrupted in-place migration needs them to resume
+pub fn keep_temporary_files() {
+    TEMPORARY.lock().unwrap().clear();
+}
+
+// the lock is only tried, a panic while it is held must not block
+fn remove_temporary_files() {
+    if let Ok(mut files) = TEMPORARY.try_lock() {
+        for path in files.drain(..) {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+pub fn set_writing(writing:bool) {
+    WRITING.store(writing, Ordering::SeqCst);
+}
+
+// unwind out of the writing when it was interrupted, resume_unwind skips the panic message
+pub fn check_interrupted() {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        panic::resume_unwind(Box::new(Interrupted));
+    }
+}
+
+// a hidden directory in the output that the files are written to, it is removed unless it was committed
+pub struct Staging {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl Staging {
+    // the partial output of an interrupted run is taken over to resume it, only its finished files are kept
+    pub fn create(output:&Path, resume:Option<&Path>) -> io::Result<Staging> {
+        let path = output.join(format!("{}{}", STAGING_PREFIX, process::id()));
+        if let Some(r) = resume {
+            fs::rename(r, &path)?;
+            for entry in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() && entry.path().extension().is_some_and(|e| e == "part") {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Staging { path, committed:false })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // move the finished files to their final place, a new torrent directory is moved as a whole so it appears at once
+    // files that aren't in the staging directory were kept in the output
+    pub fn commit(mut self, output:&Path, directory:Option<&Path>, files:&Vec<PathBuf>) -> io::Result<()> {
+        let whole = directory.map(|d| (self.path.join(d), output.join(d)));
+        match whole {
+            Some((ref staged, ref destination)) if !destination.exists() => fs::rename(staged, destination)?,
+            _ => {
+                let base = directory.map_or(PathBuf::new(), |d| d.to_path_buf());
+                for file in files {
+                    let staged = self.path.join(&base).join(file);
+                    if !staged.exists() {
+                        continue;
+                    }
+                    let destination = output.join(&base).join(file);
+                    if let Some(parent) = destination.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::rename(staged, destination)?;
+                }
+            }
+        }
+        self.committed = true;
+        fs::remove_dir_all(&self.path)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if !self.committed {
+            fs::remove_dir_all(&self.path).ok();
+        }
+    }
+}
+
+// whether the process that owns a staging directory is still running
+#[cfg(unix)]
+fn is_running(pid:u32) -> bool {
+    use migration::libc;
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid:u32) -> bool {
+    false
+}
+
+// staging directories in the output of migrations that crashed
+fn stale_staging(output:&Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(output) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    let mut stale = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(STAGING_PREFIX) {
+            continue;
+        }
+        match name[STAGING_PREFIX.len()..].parse::<u32>() {
+            Ok(pid) if pid != process::id() && is_running(pid) => {},
+            _ => stale.push(entry.path()),
+        }
+    }
+    stale
+}
+
+// offer to resume from or remove what crashed migrations left in the output, returns the one to resume from
+pub fn offer_cleanup(output:&Path) -> Option<PathBuf> {
+    let mut resume = None;
+    for path in stale_staging(output) {
+        println!("A partial output left by an interrupted migration was found in '{}'", path.display());
+        if resume.is_none() {
+            println!("Enter 'r' to resume from it or 'd' to delete it [r]");
+        } else {
+            println!("Enter 'd' to delete it or 'k' to keep it [d]");
+        }
+        let mut reply = String::new();
+        io::stdin().read_line(&mut reply).unwrap();
+        match reply.trim() {
+            "r" | "" if resume.is_none() => {
+                resume = Some(path);
+            },
+            "d" | "" => {
+                fs::remove_dir_all(&path).expect("Failed to delete folder");
+            },
+            _ => {
+                // do nothing
+            }
+        }
+    }
+    resume
+}
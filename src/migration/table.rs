@@ -0,0 +1,163 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::bip_metainfo::{Metainfo};
+use migration::cache;
+use migration::cache::PieceCache;
+use migration::layout::TorrentLayout;
+use migration::verify;
+use migration::verify::PieceResult;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+// interior pieces lie entirely inside the file, boundary pieces are shared with a neighbouring file
+#[derive(Debug, Clone, Default)]
+pub struct FileResult {
+    pub good:u32,
+    pub total:u32,
+    pub boundary_good:u32,
+    pub boundary_total:u32,
+    pub blocked:u32, // boundary pieces that can't be checked because a neighbour is unmapped
+}
+
+impl FileResult {
+    // ratio of good pieces, based on the interior pieces unless the file has none
+    pub fn ratio(&self) -> Option<f32> {
+        if self.total > 0 {
+            Some(self.good as f32 / self.total as f32)
+        } else if self.boundary_total > 0 {
+            Some(self.boundary_good as f32 / self.boundary_total as f32)
+        } else {
+            None
+        }
+    }
+
+    pub fn verified(&self) -> u32 {
+        self.good + self.boundary_good
+    }
+}
+
+// the result of every piece for the current mapping, kept up to date for the whole session
+// changing the mapping or offset of a file only rechecks the pieces touching that file
+pub struct PieceTable<'a> {
+    layout: TorrentLayout,
+    hashes: Vec<&'a [u8]>,
+    cache: &'a PieceCache,
+    pieces: Vec<Option<PieceResult>>, // None for pieces that weren't checked yet
+}
+
+impl<'a> PieceTable<'a> {
+    pub fn new(torrent_meta:&'a Metainfo, cache:&'a PieceCache) -> PieceTable<'a> {
+        let layout = TorrentLayout::new(torrent_meta);
+        let pieces = (0..layout.piece_count()).map(|_| None).collect();
+        PieceTable { layout, hashes:torrent_meta.info().pieces().collect(), cache, pieces }
+    }
+
+    // check every piece of the torrent
+    pub fn check_all(&mut self, inputs:&[SourceFile], targets:&[TargetFile]) {
+        let pieces:Vec<usize> = (0..self.layout.piece_count()).collect();
+        self.check(&pieces, inputs, targets);
+    }
+
+    // recheck the pieces of files whose mapping or offset changed, this includes the boundary pieces shared with their neighbours
+    pub fn update_files(&mut self, files:&[usize], inputs:&[SourceFile], targets:&[TargetFile]) {
+        let pieces:BTreeSet<usize> = files.iter().flat_map(|f| self.layout.file_pieces(*f)).collect();
+        let pieces:Vec<usize> = pieces.into_iter().collect();
+        self.check(&pieces, inputs, targets);
+    }
+
+    pub fn update_file(&mut self, file:usize, inputs:&[SourceFile], targets:&[TargetFile]) {
+        self.update_files(&[file], inputs, targets);
+    }
+
+    fn check(&mut self, pieces:&[usize], inputs:&[SourceFile], targets:&[TargetFile]) {
+        // take the results of pieces whose inputs didn't change from the cache
+        let identities = cache::input_identities(inputs);
+        let mut keys = HashMap::new();
+        let mut unchecked = Vec::new();
+        for piece in pieces {
+            let key = cache::piece_key(&self.layout, &identities, targets, *piece);
+            match key.as_ref().and_then(|k| self.cache.get(*piece, k)) {
+                Some(success) => {
+                    self.pieces[*piece] = Some(PieceResult { piece:*piece, files:self.layout.piece_files(*piece), checked:true, success });
+                },
+                None => {
+                    if let Some(k) = key {
+                        keys.insert(*piece, k);
+                    }
+                    unchecked.push(*piece);
+                }
+            }
+        }
+
+        // verify the other pieces, this reads the inputs sequentially and hashes in parallel
+        for result in verify::check_pieces(&self.layout, &self.hashes, inputs, targets, &unchecked) {
+            if result.checked {
+                if let Some(key) = keys.remove(&result.piece) {
+                    self.cache.insert(result.piece, key, result.success);
+                }
+            }
+            let piece = result.piece;
+            self.pieces[piece] = Some(result);
+        }
+    }
+
+    pub fn piece_result(&self, piece:usize) -> Option<&PieceResult> {
+        self.pieces[piece].as_ref()
+    }
+
+    // the result of a single target file, from the pieces checked so far
+    pub fn file_result(&self, file:usize, targets:&[TargetFile]) -> FileResult {
+        let mut info = FileResult::default();
+        for piece in self.layout.file_pieces(file) {
+            let result = match self.pieces[piece] {
+                Some(ref r) => r,
+                None => continue,
+            };
+            if !result.checked && targets[file].mapping.is_some() {
+                // the neighbour is to blame, don't count this against the file
+                info.blocked += 1;
+            } else if self.layout.is_interior(piece, file) {
+                info.total += 1;
+                if result.success { info.good += 1; }
+            } else {
+                info.boundary_total += 1;
+                if result.success { info.boundary_good += 1; }
+            }
+        }
+        info
+    }
+
+    // whether every piece of a file was checked and verified
+    pub fn file_complete(&self, file:usize) -> bool {
+        self.layout.file_pieces(file).all(|p| self.pieces[p].as_ref().is_some_and(|r| r.checked && r.success))
+    }
+
+    // the ranges of a file (relative to the start of the file) covered by verified pieces, adjacent pieces are joined
+    pub fn verified_ranges(&self, file:usize) -> Vec<Range<u64>> {
+        let mut ranges:Vec<Range<u64>> = Vec::new();
+        for piece in self.layout.file_pieces(file) {
+            if !self.pieces[piece].as_ref().is_some_and(|r| r.checked && r.success) {
+                continue;
+            }
+            let part = self.layout.file_part(piece, file);
+            match ranges.last_mut() {
+                Some(ref mut last) if last.end == part.start => last.end = part.end,
+                _ => ranges.push(part),
+            }
+        }
+        ranges
+    }
+
+    // totals over all pieces: (good, checked, blocked by an unmapped file)
+    pub fn totals(&self) -> (u32, u32, u32) {
+        let mut totals = (0, 0, 0);
+        for result in self.pieces.iter().filter_map(|p| p.as_ref()) {
+            if !result.checked {
+                totals.2 += 1;
+            } else {
+                totals.1 += 1;
+                if result.success { totals.0 += 1; }
+            }
+        }
+        totals
+    }
+}
@@ -0,0 +1,84 @@
+use ::migration::{SourceFile, TargetFile, remove_derived_input, overlay_input, map_derived_input};
+use migration::bip_metainfo::{Metainfo};
+use migration::layout::TorrentLayout;
+use migration::matching;
+use migration::overlay::Segment;
+use migration::table::PieceTable;
+
+// hash check the target with a derived input mapped in place of its input at each of the offsets, the derived
+// input is unmapped and handed back afterwards with the most pieces verified and the offset that verified them
+fn check_overlay(index:usize, inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], table:&mut PieceTable, source:usize, derived:SourceFile, offsets:&[i64]) -> (SourceFile, Option<(u32, i64)>) {
+    let original_offset = targets[index].offset;
+    let position = map_derived_input(inputs, targets, source, derived);
+    let mut best:Option<(u32, i64)> = None;
+    for &offset in offsets {
+        targets[index].offset = offset;
+        table.update_file(index, inputs, targets);
+        let good = table.file_result(index, targets).verified();
+        if best.is_none_or(|b| good > b.0) {
+            best = Some((good, offset));
+        }
+        if table.file_complete(index) {
+            break;
+        }
+    }
+    targets[index].offset = original_offset;
+    remove_derived_input(inputs, targets, source, position);
+    let derived = inputs.pop().unwrap();
+    table.update_file(index, inputs, targets);
+    (derived, best)
+}
+
+// test variants of the input of a target at the left and right aligned offsets and, when searching, the offset found
+// by a search of the middle piece, the best one replaces the input when it verifies more pieces than the input
+// the variants are generated one at a time and only read through, only the best one so far is kept
+pub fn use_best_variant<I>(index:usize, torrent_meta:&Metainfo, inputs:&mut Vec<SourceFile>, targets:&mut [TargetFile], table:&mut PieceTable, variants:I, search:bool) -> bool
+    where I: Iterator<Item=(String, Vec<Segment>)> {
+    let source = match targets[index].mapping {
+        Some(m) => m,
+        None => return false,
+    };
+    let pieces = TorrentLayout::new(torrent_meta).file_pieces(index).len() as u32;
+    let mut best:Option<(u32, String, SourceFile, i64)> = None;
+    for (i, (label, segments)) in variants.enumerate() {
+        if i == 0 {
+            println!("  {}: trying rearranged versions of the input", targets[index].path.to_string_lossy());
+        }
+        let derived = overlay_input(&inputs[source], segments, &label);
+        let mut offsets = vec![0, derived.size as i64 - targets[index].size as i64];
+        if search {
+            if let Some(offset) = matching::middle_search(index, torrent_meta, &derived) {
+                offsets.push(offset);
+            }
+        }
+        offsets.sort();
+        offsets.dedup();
+        // the whole file is only checked at the offsets where its middle piece verifies
+        offsets.retain(|o| matching::middle_check(index, torrent_meta, &derived, *o).unwrap_or(true));
+        if offsets.is_empty() {
+            continue;
+        }
+        let (derived, result) = check_overlay(index, inputs, targets, table, source, derived, &offsets);
+        if let Some((good, offset)) = result {
+            if best.as_ref().is_none_or(|b| good > b.0) {
+                best = Some((good, label, derived, offset));
+                // nothing beats a variant that verifies the whole file
+                if good == pieces {
+                    break;
+                }
+            }
+        }
+    }
+
+    let current = table.file_result(index, targets).verified();
+    match best {
+        Some((good, label, derived, offset)) if good > current => {
+            map_derived_input(inputs, targets, source, derived);
+            targets[index].offset = offset;
+            table.update_file(index, inputs, targets);
+            println!("  Using {} ({} pieces verified)", label, good);
+            true
+        },
+        _ => false,
+    }
+}
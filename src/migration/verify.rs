@@ -0,0 +1,164 @@
+use ::migration::{SourceFile, TargetFile};
+use migration::layout::TorrentLayout;
+use migration::overlay;
+use migration::overlay::InputFile;
+use migration::sha1::{Sha1, Digest};
+use migration::rayon;
+use migration::rayon::prelude::*;
+use std::cmp::{min, max};
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Debug)]
+pub struct PieceResult {
+    pub piece: usize,
+    pub files: Vec<usize>,
+    pub checked: bool, // false when one of the files in the piece is unmapped
+    pub success: bool,
+}
+
+// the kind of storage the inputs are on, this decides how they are read
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Storage {
+    Rotational, // also used for anything we can't detect, like network mounts
+    SolidState,
+}
+
+// reads ranges of the mapped inputs, the current input is kept open so consecutive pieces are read without seeking
+struct InputReader<'a> {
+    inputs: &'a [SourceFile],
+    buffer_size: usize,
+    current: Option<(usize, BufReader<InputFile<'a>>, u64)>,
+}
+
+impl<'a> InputReader<'a> {
+    fn new(inputs:&'a [SourceFile], buffer_size:usize) -> InputReader<'a> {
+        InputReader { inputs, buffer_size, current:None }
+    }
+
+    fn read(&mut self, input:usize, position:u64, length:u64, buffer:&mut Vec<u8>) {
+        if self.current.as_ref().is_none_or(|c| c.0 != input) {
+            let file = overlay::open(&self.inputs[input]).expect("Unable to open input file");
+            self.current = Some((input, BufReader::with_capacity(self.buffer_size, file), 0));
+        }
+        let current = self.current.as_mut().unwrap();
+        if current.2 != position {
+            current.1.seek(SeekFrom::Start(position)).expect("Unable to seek in file");
+            current.2 = position;
+        }
+        let read = current.1.by_ref().take(length).read_to_end(buffer).expect("Unable to read file");
+        current.2 += read as u64;
+    }
+
+    // read a range of a target file as it would be written by the migration into the buffer,
+    // bytes outside of the (offset adjusted) input are zero padding
+    fn read_target_range(&mut self, offset:i64, input:usize, start:u64, end:u64, buffer:&mut Vec<u8>) {
+        let length = (end - start) as i64;
+        let input_start = start as i64 + offset;
+        // leading padding for a negative offset, then the input data, the rest is trailing padding
+        let lead = min(max(-input_start, 0), length);
+        let data = max(min(input_start + length, self.inputs[input].size as i64) - max(input_start, 0), 0);
+        let initial = buffer.len();
+        buffer.resize(initial + lead as usize, 0);
+        if data > 0 {
+            self.read(input, max(input_start, 0) as u64, data as u64, buffer);
+        }
+        buffer.resize(initial + length as usize, 0);
+    }
+}
+
+// read a range of a target file as it would be written when mapped to the input with the given offset
+pub fn read_range(inputs:&[SourceFile], input:usize, offset:i64, start:u64, end:u64) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity((end - start) as usize);
+    InputReader::new(inputs, 1 << 16).read_target_range(offset, input, start, end, &mut buffer);
+    buffer
+}
+
+#[cfg(target_os = "linux")]
+fn is_rotational(path:&Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let dev = path.metadata().ok()?.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let device = Path::new("/sys/dev/block").join(format!("{}:{}", major, minor));
+    // partitions don't have a queue of their own, the parent device has
+    for queue in &[device.join("queue"), device.join("../queue")] {
+        if let Ok(value) = fs::read_to_string(queue.join("rotational")) {
+            return Some(value.trim() == "1");
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_path:&Path) -> Option<bool> {
+    None
+}
+
+// only use parallel reads when we are sure all mapped inputs are on solid state storage
+fn detect_storage(inputs:&[SourceFile], targets:&[TargetFile]) -> Storage {
+    let solid_state = targets.iter().filter_map(|t| t.mapping).all(|m| is_rotational(&inputs[m].path) == Some(false));
+    if solid_state { Storage::SolidState } else { Storage::Rotational }
+}
+
+// assemble a batch of consecutive pieces, pieces with an unmapped file are not read
+fn read_batch(reader:&mut InputReader, layout:&TorrentLayout, targets:&[TargetFile], pieces:&[usize]) -> Vec<(PieceResult, Option<Vec<u8>>)> {
+    pieces.iter().map(|piece| {
+        let files = layout.piece_files(*piece);
+        let checked = files.iter().all(|f| targets[*f].mapping.is_some());
+        let result = PieceResult { piece:*piece, files, checked, success:false };
+        if !checked {
+            return (result, None);
+        }
+        let mut buffer:Vec<u8> = Vec::with_capacity(layout.piece_size(*piece) as usize);
+        for f in &result.files {
+            let part = layout.file_part(*piece, *f);
+            reader.read_target_range(targets[*f].offset, targets[*f].mapping.unwrap(), part.start, part.end, &mut buffer);
+        }
+        (result, Some(buffer))
+    }).collect()
+}
+
+// read a run of pieces sequentially, hashing each batch while the next one is being read
+fn check_segment(layout:&TorrentLayout, hashes:&[&[u8]], inputs:&[SourceFile], targets:&[TargetFile], pieces:&[usize], buffer_size:usize, batch_size:usize) -> Vec<PieceResult> {
+    let mut reader = InputReader::new(inputs, buffer_size);
+    let mut results = Vec::with_capacity(pieces.len());
+    let mut batches = pieces.chunks(batch_size);
+    let mut batch = batches.next().map_or(Vec::new(), |b| read_batch(&mut reader, layout, targets, b));
+    while !batch.is_empty() {
+        let next = batches.next();
+        let (hashed, next_batch) = rayon::join(
+            || batch.into_par_iter().map(|(mut result, data)| {
+                if let Some(data) = data {
+                    result.success = Sha1::digest(&data).as_slice() == hashes[result.piece];
+                }
+                result
+            }).collect::<Vec<PieceResult>>(),
+            || next.map_or(Vec::new(), |b| read_batch(&mut reader, layout, targets, b))
+        );
+        results.extend(hashed);
+        batch = next_batch;
+    }
+    results
+}
+
+// verify the given pieces (in ascending order) against the current mapping
+pub fn check_pieces(layout:&TorrentLayout, hashes:&[&[u8]], inputs:&[SourceFile], targets:&[TargetFile], pieces:&[usize]) -> Vec<PieceResult> {
+    if pieces.is_empty() {
+        return Vec::new();
+    }
+    // rotational storage gets a single sequential reader with large buffers,
+    // on solid state storage the piece range is split over multiple readers
+    let (readers, buffer_size, batch_bytes) = match detect_storage(inputs, targets) {
+        Storage::Rotational => (1, 8 << 20, 64 << 20),
+        Storage::SolidState => (rayon::current_num_threads(), 1 << 20, 16 << 20),
+    };
+    let piece_length = max(layout.piece_size(pieces[0]), 1) as usize;
+    let batch_size = max(batch_bytes / piece_length, 1);
+    let segment_size = max(pieces.len().div_ceil(readers), 1);
+    let segments:Vec<Vec<PieceResult>> = pieces.par_chunks(segment_size).map(|segment| {
+        check_segment(layout, hashes, inputs, targets, segment, buffer_size, batch_size)
+    }).collect();
+    segments.into_iter().flat_map(|s| s.into_iter()).collect()
+}